@@ -0,0 +1,87 @@
+mod dropbox;
+mod local;
+mod s3;
+mod sftp;
+mod webdav;
+
+use std::path::Path;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use tokio::fs;
+
+pub use self::dropbox::DropboxBackend;
+pub use local::LocalBackend;
+pub use s3::S3Backend;
+pub use sftp::SftpBackend;
+pub use webdav::WebDavBackend;
+
+// 同期先にあるファイルの情報
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RemoteFile {
+    pub name: String,
+    pub modified: DateTime<Utc>,
+    // 内容が変わると変化する値。ETagなど
+    pub revision: Option<String>,
+    // 内容から計算したハッシュ。SyncBackend::content_hashと同じ方法で計算されている
+    #[serde(default)]
+    pub content_hash: Option<String>,
+}
+
+// カーソルの時点からの変更
+#[derive(Debug)]
+pub struct Changes {
+    // 追加または更新されたファイル
+    pub updated: Vec<RemoteFile>,
+    // 削除されたファイルの名前
+    pub deleted: Vec<String>,
+    // 次回の変更の取得に使う
+    pub cursor: String,
+}
+
+// 同期先。パスは "/pages/2020-03-29-2020-04-04.json" のように同期先のルートからの絶対パスで指定する
+#[async_trait]
+pub trait SyncBackend: Send + Sync {
+    // フォルダ内のファイルを取得する。フォルダが存在しなければ空になる
+    async fn list(&self, dir: &str) -> Result<Vec<RemoteFile>>;
+    async fn get(&self, path: &str) -> Result<Vec<u8>>;
+    // 親のフォルダが存在しなければ作成する。書き込んだファイルの情報を返す
+    async fn put(&self, path: &str, contents: Vec<u8>) -> Result<RemoteFile>;
+    #[allow(dead_code)]
+    async fn delete(&self, path: &str) -> Result<()>;
+    // ファイルが存在しなければNoneを返す
+    async fn metadata(&self, path: &str) -> Result<Option<RemoteFile>>;
+
+    // 一覧と、次回に変更だけを取得するためのカーソルを取得する。
+    // カーソルに対応していない同期先ではNoneになる
+    async fn list_with_cursor(&self, dir: &str) -> Result<(Vec<RemoteFile>, Option<String>)> {
+        Ok((self.list(dir).await?, None))
+    }
+
+    // カーソルの時点からの変更を取得する。カーソルが無効になっていればNoneを返す
+    async fn list_changes(&self, _dir: &str, _cursor: &str) -> Result<Option<Changes>> {
+        Ok(None)
+    }
+
+    // ファイルが存在しない場合だけ書き込む。既に存在すればNoneを返す。
+    // 不可分に書き込めない同期先では、確認してから書き込むまでの間に他の端末が書き込むことがある
+    async fn put_new(&self, path: &str, contents: Vec<u8>) -> Result<Option<RemoteFile>> {
+        if self.metadata(path).await?.is_some() {
+            return Ok(None);
+        }
+        Ok(Some(self.put(path, contents).await?))
+    }
+
+    // ファイルに書き込む。大きなファイルをメモリに溜めずに受け取れる同期先では上書きする
+    async fn get_to_file(&self, path: &str, dest: &Path) -> Result<()> {
+        let contents = self.get(path).await?;
+        fs::write(dest, contents).await?;
+        Ok(())
+    }
+
+    // RemoteFile::content_hashと比較するためのハッシュを計算する。対応していなければNoneを返す
+    fn content_hash(&self, _contents: &[u8]) -> Option<String> {
+        None
+    }
+}
@@ -0,0 +1,119 @@
+use std::path::Path;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use reqwest::Client;
+
+use super::{Changes, RemoteFile, SyncBackend};
+use crate::dropbox::{self, AccessToken, FileInfo, Metadata};
+
+// Dropboxのアプリフォルダに同期する
+pub struct DropboxBackend {
+    client: Client,
+    access_token: AccessToken,
+}
+
+impl DropboxBackend {
+    pub fn new(client: Client, access_token: AccessToken) -> Self {
+        Self {
+            client,
+            access_token,
+        }
+    }
+}
+
+impl From<FileInfo> for RemoteFile {
+    fn from(info: FileInfo) -> Self {
+        Self {
+            name: info.name,
+            modified: info.client_modified,
+            revision: Some(info.rev),
+            content_hash: info.content_hash,
+        }
+    }
+}
+
+#[async_trait]
+impl SyncBackend for DropboxBackend {
+    async fn list(&self, dir: &str) -> Result<Vec<RemoteFile>> {
+        let (files, _) = self.list_with_cursor(dir).await?;
+        Ok(files)
+    }
+
+    async fn get(&self, path: &str) -> Result<Vec<u8>> {
+        let (_, contents) = dropbox::download_file(&self.client, &self.access_token, path).await?;
+        Ok(contents)
+    }
+
+    async fn get_to_file(&self, path: &str, dest: &Path) -> Result<()> {
+        dropbox::download_file_to(&self.client, &self.access_token, path, dest).await?;
+        Ok(())
+    }
+
+    async fn put(&self, path: &str, contents: Vec<u8>) -> Result<RemoteFile> {
+        let info = dropbox::upload_file(&self.client, &self.access_token, path, contents).await?;
+        Ok(RemoteFile::from(info))
+    }
+
+    async fn put_new(&self, path: &str, contents: Vec<u8>) -> Result<Option<RemoteFile>> {
+        let info =
+            dropbox::upload_new_file(&self.client, &self.access_token, path, contents).await?;
+        Ok(info.map(RemoteFile::from))
+    }
+
+    async fn delete(&self, path: &str) -> Result<()> {
+        dropbox::delete_file(&self.client, &self.access_token, path).await
+    }
+
+    async fn metadata(&self, path: &str) -> Result<Option<RemoteFile>> {
+        let info = dropbox::get_metadata(&self.client, &self.access_token, path).await?;
+        Ok(info.map(RemoteFile::from))
+    }
+
+    async fn list_with_cursor(&self, dir: &str) -> Result<(Vec<RemoteFile>, Option<String>)> {
+        // 一覧の取得では何も書き込まない。フォルダはアップロードするときに作成される
+        match dropbox::list_files(&self.client, &self.access_token, dir).await? {
+            Some((files, cursor)) => Ok((
+                files.into_iter().map(RemoteFile::from).collect(),
+                Some(cursor),
+            )),
+            None => Ok((Vec::new(), None)),
+        }
+    }
+
+    async fn list_changes(&self, _dir: &str, cursor: &str) -> Result<Option<Changes>> {
+        let (entries, cursor) =
+            match dropbox::list_changes(&self.client, &self.access_token, cursor).await? {
+                Some(changes) => changes,
+                None => return Ok(None),
+            };
+
+        let mut changes = Changes {
+            updated: Vec::new(),
+            deleted: Vec::new(),
+            cursor,
+        };
+        // 同じファイルが何度も含まれている場合は後の変更を優先する
+        for entry in entries {
+            match entry {
+                Metadata::File(info) => {
+                    changes.updated.retain(|file| file.name != info.name);
+                    changes.deleted.retain(|name| *name != info.name);
+                    changes.updated.push(RemoteFile::from(info));
+                }
+                Metadata::Deleted { name } => {
+                    changes.updated.retain(|file| file.name != name);
+                    changes.deleted.retain(|name2| *name2 != name);
+                    changes.deleted.push(name);
+                }
+                Metadata::Folder => {}
+            }
+        }
+
+        Ok(Some(changes))
+    }
+
+    fn content_hash(&self, contents: &[u8]) -> Option<String> {
+        Some(dropbox::content_hash(contents))
+    }
+}
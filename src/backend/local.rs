@@ -0,0 +1,149 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use tokio::fs;
+use tokio::stream::StreamExt;
+
+use super::{RemoteFile, SyncBackend};
+use crate::storage;
+
+// ネットワーク上の共有フォルダなど、マウントされたディレクトリに同期する
+pub struct LocalBackend {
+    root: PathBuf,
+}
+
+impl LocalBackend {
+    pub fn new(root: &Path) -> Self {
+        Self {
+            root: root.to_path_buf(),
+        }
+    }
+
+    fn local_path(&self, path: &str) -> Result<PathBuf> {
+        let relative = path.trim_start_matches('/');
+
+        // 同期先の外を指さないようにする
+        if relative.split('/').any(|part| part == "..") {
+            return Err(anyhow!("不正なパスです: {}", path));
+        }
+
+        Ok(self.root.join(relative))
+    }
+}
+
+// 更新日時とサイズをリビジョンとして使う
+fn remote_file(name: String, metadata: &std::fs::Metadata) -> Result<RemoteFile> {
+    let modified = DateTime::<Utc>::from(metadata.modified()?);
+    Ok(RemoteFile {
+        name,
+        modified,
+        revision: Some(format!("{}-{}", modified.to_rfc3339(), metadata.len())),
+        content_hash: None,
+    })
+}
+
+#[async_trait]
+impl SyncBackend for LocalBackend {
+    async fn list(&self, dir: &str) -> Result<Vec<RemoteFile>> {
+        let dir = self.local_path(dir)?;
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut files = Vec::new();
+
+        let mut entries = fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next().await {
+            let entry = entry?;
+            let metadata = entry.metadata().await?;
+
+            // 書き込み途中の一時ファイルは含めない
+            let name = entry.file_name().to_string_lossy().to_string();
+            if metadata.is_file() && !name.ends_with(".tmp") {
+                files.push(remote_file(name, &metadata)?);
+            }
+        }
+
+        Ok(files)
+    }
+
+    async fn get(&self, path: &str) -> Result<Vec<u8>> {
+        Ok(fs::read(self.local_path(path)?).await?)
+    }
+
+    async fn put(&self, path: &str, contents: Vec<u8>) -> Result<RemoteFile> {
+        let local_path = self.local_path(path)?;
+        if let Some(parent) = local_path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+
+        // 他の端末が書き込み途中のファイルを読まないようにする
+        storage::write_atomically(&local_path, &contents).await?;
+
+        self.metadata(path)
+            .await?
+            .ok_or_else(|| anyhow!("{}に書き込めませんでした", path))
+    }
+
+    async fn delete(&self, path: &str) -> Result<()> {
+        fs::remove_file(self.local_path(path)?).await?;
+        Ok(())
+    }
+
+    async fn metadata(&self, path: &str) -> Result<Option<RemoteFile>> {
+        let path = self.local_path(path)?;
+        if !path.is_file() {
+            return Ok(None);
+        }
+
+        let metadata = fs::metadata(&path).await?;
+        let name = path.file_name().unwrap().to_string_lossy().to_string();
+        Ok(Some(remote_file(name, &metadata)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[tokio::test]
+    async fn test_local_backend() {
+        let dir = TempDir::new();
+        let backend = LocalBackend::new(dir.path());
+
+        // 存在しないフォルダは空になる
+        assert!(backend.list("/pages").await.unwrap().is_empty());
+        assert!(backend.metadata("/pages/a.json").await.unwrap().is_none());
+
+        backend
+            .put("/pages/a.json", b"page".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(
+            b"page".to_vec(),
+            backend.get("/pages/a.json").await.unwrap()
+        );
+
+        let files = backend.list("/pages").await.unwrap();
+        assert_eq!(1, files.len());
+        assert_eq!("a.json", files[0].name);
+        assert_eq!(
+            "a.json",
+            backend
+                .metadata("/pages/a.json")
+                .await
+                .unwrap()
+                .unwrap()
+                .name
+        );
+
+        backend.delete("/pages/a.json").await.unwrap();
+        assert!(backend.list("/pages").await.unwrap().is_empty());
+
+        // 同期先の外には書き込めない
+        assert!(backend.put("/../a.json", Vec::new()).await.is_err());
+    }
+}
@@ -0,0 +1,284 @@
+use std::env;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Context as _, Result};
+use async_trait::async_trait;
+use chrono::{TimeZone, Utc};
+use ssh2::{CheckResult, ErrorCode, FileStat, KnownHostFileKind, Session, Sftp};
+use tokio::task;
+
+use super::{RemoteFile, SyncBackend};
+use crate::config::SftpConfig;
+
+// LIBSSH2_FX_NO_SUCH_FILE
+const NO_SUCH_FILE: i32 = 2;
+
+// SSHで接続できるサーバーのディレクトリにSFTPで同期する
+pub struct SftpBackend {
+    config: Arc<SftpConfig>,
+    // 最初に使うときに接続する。ssh2はブロックするので別のスレッドから使う
+    sftp: Arc<Mutex<Option<Sftp>>>,
+}
+
+fn known_hosts_path(config: &SftpConfig) -> Result<PathBuf> {
+    match &config.known_hosts {
+        Some(path) => Ok(path.clone()),
+        None => {
+            let home = env::var("HOME").context("HOMEが設定されていません")?;
+            Ok(Path::new(&home).join(".ssh").join("known_hosts"))
+        }
+    }
+}
+
+fn connect(config: &SftpConfig) -> Result<Sftp> {
+    let tcp = TcpStream::connect((config.host.as_str(), config.port))
+        .with_context(|| format!("{}に接続できませんでした", config.host))?;
+
+    let mut session = Session::new()?;
+    session.set_tcp_stream(tcp);
+    session.handshake()?;
+
+    // 接続先が本物か確かめる
+    let mut known_hosts = session.known_hosts()?;
+    let known_hosts_path = known_hosts_path(config)?;
+    known_hosts
+        .read_file(&known_hosts_path, KnownHostFileKind::OpenSSH)
+        .with_context(|| format!("{}を読み込めませんでした", known_hosts_path.display()))?;
+    let (host_key, _) = session
+        .host_key()
+        .ok_or_else(|| anyhow!("ホスト鍵を取得できませんでした"))?;
+    match known_hosts.check_port(&config.host, config.port, host_key) {
+        CheckResult::Match => {}
+        CheckResult::Mismatch => {
+            return Err(anyhow!("{}のホスト鍵が一致しません", config.host));
+        }
+        _ => {
+            return Err(anyhow!(
+                "{}のホスト鍵が登録されていません。sshで一度接続してください",
+                config.host
+            ));
+        }
+    }
+
+    // 秘密鍵が設定されていなければssh-agentを使う
+    match &config.private_key {
+        Some(private_key) => session
+            .userauth_pubkey_file(&config.username, None, private_key, None)
+            .context("公開鍵で認証できませんでした")?,
+        None => session
+            .userauth_agent(&config.username)
+            .context("ssh-agentで認証できませんでした")?,
+    }
+
+    Ok(session.sftp()?)
+}
+
+fn is_no_such_file(err: &ssh2::Error) -> bool {
+    err.code() == ErrorCode::SFTP(NO_SUCH_FILE)
+}
+
+// 更新日時とサイズをリビジョンとして使う
+fn remote_file(name: String, stat: &FileStat) -> RemoteFile {
+    let mtime = stat.mtime.unwrap_or(0);
+    RemoteFile {
+        name,
+        modified: Utc.timestamp(mtime as i64, 0),
+        revision: Some(format!("{}-{}", mtime, stat.size.unwrap_or(0))),
+        content_hash: None,
+    }
+}
+
+fn file_name_of(path: &str) -> String {
+    path.rsplit('/').next().unwrap_or(path).to_string()
+}
+
+impl SftpBackend {
+    pub fn new(config: SftpConfig) -> Self {
+        Self {
+            config: Arc::new(config),
+            sftp: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    fn remote_path(&self, path: &str) -> Result<PathBuf> {
+        let relative = path.trim_start_matches('/');
+
+        // 同期先の外を指さないようにする
+        if relative.split('/').any(|part| part == "..") {
+            return Err(anyhow!("不正なパスです: {}", path));
+        }
+
+        Ok(Path::new(&self.config.path).join(relative))
+    }
+
+    // 接続してから別のスレッドで実行する
+    async fn run<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&Sftp) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let config = self.config.clone();
+        let sftp = self.sftp.clone();
+
+        task::spawn_blocking(move || {
+            let mut sftp = sftp.lock().unwrap();
+            if sftp.is_none() {
+                *sftp = Some(connect(&config)?);
+            }
+
+            f(sftp.as_ref().unwrap())
+        })
+        .await?
+    }
+}
+
+#[async_trait]
+impl SyncBackend for SftpBackend {
+    async fn list(&self, dir: &str) -> Result<Vec<RemoteFile>> {
+        let dir = self.remote_path(dir)?;
+
+        self.run(move |sftp| {
+            let entries = match sftp.readdir(&dir) {
+                Ok(entries) => entries,
+                Err(err) if is_no_such_file(&err) => return Ok(Vec::new()),
+                Err(err) => return Err(err.into()),
+            };
+
+            // 書き込み途中の一時ファイルは含めない
+            Ok(entries
+                .into_iter()
+                .filter(|(_, stat)| stat.is_file())
+                .filter_map(|(path, stat)| {
+                    let name = path.file_name()?.to_string_lossy().to_string();
+                    if name.ends_with(".tmp") {
+                        None
+                    } else {
+                        Some(remote_file(name, &stat))
+                    }
+                })
+                .collect())
+        })
+        .await
+    }
+
+    async fn get(&self, path: &str) -> Result<Vec<u8>> {
+        let remote_path = self.remote_path(path)?;
+
+        self.run(move |sftp| {
+            let mut contents = Vec::new();
+            sftp.open(&remote_path)?.read_to_end(&mut contents)?;
+            Ok(contents)
+        })
+        .await
+    }
+
+    async fn put(&self, path: &str, contents: Vec<u8>) -> Result<RemoteFile> {
+        let remote_path = self.remote_path(path)?;
+        let root = PathBuf::from(&self.config.path);
+        let name = file_name_of(path);
+
+        self.run(move |sftp| {
+            // 親のディレクトリを上から順に作成する
+            let parent = remote_path.parent().unwrap();
+            let mut dir = root;
+            for component in parent.strip_prefix(&dir).unwrap_or(parent).components() {
+                dir.push(component);
+                if sftp.stat(&dir).is_err() {
+                    sftp.mkdir(&dir, 0o755)?;
+                }
+            }
+
+            // 他の端末が書き込み途中のファイルを読まないように、一時ファイルに書き込んでから置き換える。
+            // OpenSSHのSFTPサーバーは上書きできないので先に削除する
+            let temp_path = PathBuf::from(format!("{}.tmp", remote_path.display()));
+            sftp.create(&temp_path)?.write_all(&contents)?;
+            if sftp.stat(&remote_path).is_ok() {
+                sftp.unlink(&remote_path)?;
+            }
+            sftp.rename(&temp_path, &remote_path, None)?;
+
+            Ok(remote_file(name, &sftp.stat(&remote_path)?))
+        })
+        .await
+    }
+
+    async fn delete(&self, path: &str) -> Result<()> {
+        let remote_path = self.remote_path(path)?;
+
+        self.run(move |sftp| {
+            sftp.unlink(&remote_path)?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn metadata(&self, path: &str) -> Result<Option<RemoteFile>> {
+        let remote_path = self.remote_path(path)?;
+        let name = file_name_of(path);
+
+        self.run(move |sftp| match sftp.stat(&remote_path) {
+            Ok(stat) if stat.is_file() => Ok(Some(remote_file(name, &stat))),
+            Ok(_) => Ok(None),
+            Err(err) if is_no_such_file(&err) => Ok(None),
+            Err(err) => Err(err.into()),
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // ローカルで起動したsshdに対して実行する。ホスト鍵はknown_hostsに登録しておく
+    // 例: DIARY2_TEST_SFTP_HOST=localhost DIARY2_TEST_SFTP_PORT=2222 DIARY2_TEST_SFTP_USERNAME=me \
+    //     DIARY2_TEST_SFTP_PRIVATE_KEY=~/.ssh/id_ed25519 cargo test -- --ignored
+    #[tokio::test]
+    #[ignore]
+    async fn test_sftp_backend() {
+        let env = |name: &str| env::var(name).unwrap();
+        let path = env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let backend = SftpBackend::new(SftpConfig {
+            host: env("DIARY2_TEST_SFTP_HOST"),
+            port: env("DIARY2_TEST_SFTP_PORT").parse().unwrap(),
+            username: env("DIARY2_TEST_SFTP_USERNAME"),
+            private_key: env::var("DIARY2_TEST_SFTP_PRIVATE_KEY")
+                .ok()
+                .map(PathBuf::from),
+            known_hosts: env::var("DIARY2_TEST_SFTP_KNOWN_HOSTS")
+                .ok()
+                .map(PathBuf::from),
+            path: path.to_string_lossy().to_string(),
+        });
+
+        assert!(backend.list("/pages").await.unwrap().is_empty());
+        assert!(backend.metadata("/pages/a.json").await.unwrap().is_none());
+
+        let file = backend
+            .put("/pages/a.json", b"page".to_vec())
+            .await
+            .unwrap();
+        assert_eq!("a.json", file.name);
+        assert_eq!(
+            b"page".to_vec(),
+            backend.get("/pages/a.json").await.unwrap()
+        );
+
+        // サイズが変われば更新日時が同じでもリビジョンが変わる
+        let updated = backend
+            .put("/pages/a.json", b"page2".to_vec())
+            .await
+            .unwrap();
+        assert_ne!(file.revision, updated.revision);
+
+        let files = backend.list("/pages").await.unwrap();
+        assert_eq!(1, files.len());
+        assert_eq!(updated.revision, files[0].revision);
+
+        backend.delete("/pages/a.json").await.unwrap();
+        assert!(backend.list("/pages").await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,394 @@
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Context as _, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, TimeZone, Utc};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use reqwest::{header, Client, Method, RequestBuilder, StatusCode};
+
+use super::{RemoteFile, SyncBackend};
+
+const DAV_NAMESPACE: &str = "DAV:";
+
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<d:propfind xmlns:d="DAV:">
+  <d:prop>
+    <d:resourcetype/>
+    <d:getetag/>
+    <d:getlastmodified/>
+  </d:prop>
+</d:propfind>"#;
+
+// パスの要素でエスケープする文字
+const PATH_SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'/')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+// WebDAVサーバーのフォルダに同期する
+pub struct WebDavBackend {
+    client: Client,
+    // 同期先のフォルダのURL
+    url: String,
+    username: Option<String>,
+    password: Option<String>,
+    // 作成済みのフォルダ。何度もMKCOLしないようにする
+    created_dirs: Mutex<HashSet<String>>,
+}
+
+// PROPFINDで取得したリソースの情報
+#[derive(Debug)]
+struct Resource {
+    href: String,
+    is_collection: bool,
+    etag: Option<String>,
+    modified: Option<DateTime<Utc>>,
+}
+
+impl WebDavBackend {
+    pub fn new(
+        client: Client,
+        url: &str,
+        username: Option<String>,
+        password: Option<String>,
+    ) -> Self {
+        Self {
+            client,
+            url: url.trim_end_matches('/').to_string(),
+            username,
+            password,
+            created_dirs: Mutex::new(HashSet::new()),
+        }
+    }
+
+    fn url(&self, path: &str) -> String {
+        let mut url = self.url.clone();
+        for segment in path.split('/').filter(|s| !s.is_empty()) {
+            url.push('/');
+            url.extend(utf8_percent_encode(segment, PATH_SEGMENT));
+        }
+
+        url
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        let request = self.client.request(method, &self.url(path));
+        match &self.username {
+            Some(username) => request.basic_auth(username, self.password.as_ref()),
+            None => request,
+        }
+    }
+
+    async fn propfind(&self, path: &str, depth: &str) -> Result<Option<Vec<Resource>>> {
+        let res = self
+            .request(Method::from_bytes(b"PROPFIND")?, path)
+            .header("Depth", depth)
+            .header(header::CONTENT_TYPE, "application/xml; charset=utf-8")
+            .body(PROPFIND_BODY)
+            .send()
+            .await?;
+
+        if res.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        let xml = res.error_for_status()?.text().await?;
+        let resources = parse_multistatus(&xml)
+            .with_context(|| format!("{}の情報を読み込めませんでした", path))?;
+
+        Ok(Some(resources))
+    }
+
+    // 同期先のフォルダから親のフォルダまでを上から順に作成する
+    async fn create_parent_dirs(&self, path: &str) -> Result<()> {
+        let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+
+        let mut dirs = vec![String::new()];
+        for segment in &segments[..segments.len().saturating_sub(1)] {
+            dirs.push(format!("{}/{}", dirs.last().unwrap(), segment));
+        }
+
+        for dir in dirs {
+            if self.created_dirs.lock().unwrap().contains(&dir) {
+                continue;
+            }
+
+            let res = self
+                .request(Method::from_bytes(b"MKCOL")?, &dir)
+                .send()
+                .await?;
+
+            // 既に存在する場合は405になる
+            if !res.status().is_success() && res.status() != StatusCode::METHOD_NOT_ALLOWED {
+                return Err(anyhow!(
+                    "フォルダ{}を作成できませんでした: {}",
+                    dir,
+                    res.status()
+                ));
+            }
+
+            self.created_dirs.lock().unwrap().insert(dir);
+        }
+
+        Ok(())
+    }
+}
+
+fn file_name_of(href: &str) -> String {
+    let name = href.trim_end_matches('/').rsplit('/').next().unwrap_or("");
+    percent_decode_str(name).decode_utf8_lossy().to_string()
+}
+
+fn remote_file(resource: Resource) -> RemoteFile {
+    RemoteFile {
+        name: file_name_of(&resource.href),
+        // 更新日時を返さないサーバーもある
+        modified: resource.modified.unwrap_or_else(|| Utc.timestamp(0, 0)),
+        revision: resource.etag,
+        content_hash: None,
+    }
+}
+
+// PROPFINDのレスポンス (207 Multi-Status) を読み込む
+fn parse_multistatus(xml: &str) -> Result<Vec<Resource>> {
+    let doc = roxmltree::Document::parse(xml)?;
+
+    let mut resources = Vec::new();
+    for response in doc
+        .descendants()
+        .filter(|n| n.has_tag_name((DAV_NAMESPACE, "response")))
+    {
+        let href = response
+            .children()
+            .find(|n| n.has_tag_name((DAV_NAMESPACE, "href")))
+            .and_then(|n| n.text())
+            .ok_or_else(|| anyhow!("hrefがありません"))?;
+
+        // 取得できたプロパティだけを見る
+        let props: Vec<_> = response
+            .children()
+            .filter(|n| n.has_tag_name((DAV_NAMESPACE, "propstat")))
+            .filter(|propstat| {
+                propstat
+                    .children()
+                    .find(|n| n.has_tag_name((DAV_NAMESPACE, "status")))
+                    .and_then(|n| n.text())
+                    .map_or(true, |status| status.contains(" 200 "))
+            })
+            .flat_map(|propstat| propstat.children())
+            .filter(|n| n.has_tag_name((DAV_NAMESPACE, "prop")))
+            .flat_map(|prop| prop.children())
+            .filter(|n| n.is_element())
+            .collect();
+
+        let find_text = |name: &str| {
+            props
+                .iter()
+                .find(|n| n.has_tag_name((DAV_NAMESPACE, name)))
+                .and_then(|n| n.text())
+                .map(|text| text.trim().to_string())
+        };
+
+        let is_collection = props.iter().any(|n| {
+            n.has_tag_name((DAV_NAMESPACE, "resourcetype"))
+                && n.children()
+                    .any(|n| n.has_tag_name((DAV_NAMESPACE, "collection")))
+        });
+
+        let modified = find_text("getlastmodified")
+            .and_then(|date| DateTime::parse_from_rfc2822(&date).ok())
+            .map(|date| date.with_timezone(&Utc));
+
+        resources.push(Resource {
+            href: href.trim().to_string(),
+            is_collection,
+            etag: find_text("getetag"),
+            modified,
+        });
+    }
+
+    Ok(resources)
+}
+
+#[async_trait]
+impl SyncBackend for WebDavBackend {
+    async fn list(&self, dir: &str) -> Result<Vec<RemoteFile>> {
+        let resources = match self.propfind(dir, "1").await? {
+            Some(resources) => resources,
+            None => return Ok(Vec::new()),
+        };
+
+        // フォルダ自身とサブフォルダは含めない
+        Ok(resources
+            .into_iter()
+            .filter(|resource| !resource.is_collection)
+            .map(remote_file)
+            .collect())
+    }
+
+    async fn get(&self, path: &str) -> Result<Vec<u8>> {
+        let bytes = self
+            .request(Method::GET, path)
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+
+        Ok(bytes.to_vec())
+    }
+
+    async fn put(&self, path: &str, contents: Vec<u8>) -> Result<RemoteFile> {
+        self.create_parent_dirs(path).await?;
+
+        let res = self
+            .request(Method::PUT, path)
+            .header(header::CONTENT_TYPE, "application/octet-stream")
+            .body(contents)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        // ETagを返すサーバーならPROPFINDしなくてよい
+        if let Some(etag) = res.headers().get(header::ETAG) {
+            return Ok(RemoteFile {
+                name: file_name_of(path),
+                modified: Utc::now(),
+                revision: Some(etag.to_str()?.to_string()),
+                content_hash: None,
+            });
+        }
+
+        self.metadata(path)
+            .await?
+            .ok_or_else(|| anyhow!("{}に書き込めませんでした", path))
+    }
+
+    async fn delete(&self, path: &str) -> Result<()> {
+        self.request(Method::DELETE, path)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+
+    async fn metadata(&self, path: &str) -> Result<Option<RemoteFile>> {
+        let resources = match self.propfind(path, "0").await? {
+            Some(resources) => resources,
+            None => return Ok(None),
+        };
+
+        Ok(resources
+            .into_iter()
+            .find(|resource| !resource.is_collection)
+            .map(remote_file))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_multistatus() {
+        let xml = r#"<?xml version="1.0" encoding="utf-8"?>
+<d:multistatus xmlns:d="DAV:">
+  <d:response>
+    <d:href>/dav/diary/pages/</d:href>
+    <d:propstat>
+      <d:prop>
+        <d:resourcetype><d:collection/></d:resourcetype>
+        <d:getlastmodified>Sun, 05 Apr 2020 10:00:00 GMT</d:getlastmodified>
+      </d:prop>
+      <d:status>HTTP/1.1 200 OK</d:status>
+    </d:propstat>
+  </d:response>
+  <d:response>
+    <d:href>/dav/diary/pages/2020-03-29-2020-04-04.json</d:href>
+    <d:propstat>
+      <d:prop>
+        <d:resourcetype/>
+        <d:getetag>"abc"</d:getetag>
+        <d:getlastmodified>Sun, 05 Apr 2020 10:00:00 GMT</d:getlastmodified>
+      </d:prop>
+      <d:status>HTTP/1.1 200 OK</d:status>
+    </d:propstat>
+  </d:response>
+  <d:response>
+    <d:href>/dav/diary/images/a%20b.png</d:href>
+    <d:propstat>
+      <d:prop><d:resourcetype/></d:prop>
+      <d:status>HTTP/1.1 200 OK</d:status>
+    </d:propstat>
+    <d:propstat>
+      <d:prop><d:getetag/></d:prop>
+      <d:status>HTTP/1.1 404 Not Found</d:status>
+    </d:propstat>
+  </d:response>
+</d:multistatus>"#;
+
+        let resources = parse_multistatus(xml).unwrap();
+        assert_eq!(3, resources.len());
+        assert!(resources[0].is_collection);
+
+        let file = remote_file(resources.into_iter().nth(1).unwrap());
+        assert_eq!("2020-03-29-2020-04-04.json", file.name);
+        assert_eq!(Some(String::from("\"abc\"")), file.revision);
+        assert_eq!(Utc.ymd(2020, 4, 5).and_hms(10, 0, 0), file.modified);
+
+        let resources = parse_multistatus(xml).unwrap();
+        let file = remote_file(resources.into_iter().nth(2).unwrap());
+        assert_eq!("a b.png", file.name);
+        assert_eq!(None, file.revision);
+    }
+
+    // ローカルで起動したWebDAVサーバーに対して実行する。
+    // 例: rclone serve webdav /tmp/dav --addr 127.0.0.1:8080
+    //     DIARY2_TEST_WEBDAV_URL=http://127.0.0.1:8080 cargo test -- --ignored
+    #[tokio::test]
+    #[ignore]
+    async fn test_webdav_backend() {
+        let url = std::env::var("DIARY2_TEST_WEBDAV_URL").unwrap();
+        let backend = WebDavBackend::new(
+            Client::new(),
+            &format!("{}/{}", url, uuid::Uuid::new_v4()),
+            std::env::var("DIARY2_TEST_WEBDAV_USERNAME").ok(),
+            std::env::var("DIARY2_TEST_WEBDAV_PASSWORD").ok(),
+        );
+
+        assert!(backend.list("/pages").await.unwrap().is_empty());
+        assert!(backend.metadata("/pages/a.json").await.unwrap().is_none());
+
+        let file = backend
+            .put("/pages/a.json", b"page".to_vec())
+            .await
+            .unwrap();
+        assert_eq!("a.json", file.name);
+        assert_eq!(
+            b"page".to_vec(),
+            backend.get("/pages/a.json").await.unwrap()
+        );
+
+        // 書き換えるとETagが変わる
+        let files = backend.list("/pages").await.unwrap();
+        assert_eq!(1, files.len());
+        let file = backend
+            .put("/pages/a.json", b"page2".to_vec())
+            .await
+            .unwrap();
+        assert_ne!(files[0].revision, file.revision);
+
+        backend.delete("/pages/a.json").await.unwrap();
+        assert!(backend.list("/pages").await.unwrap().is_empty());
+    }
+}
@@ -1,25 +1,40 @@
 use std::borrow::Cow;
+use std::collections::HashMap;
+use std::env;
+use std::io::{self, Write as _};
 use std::iter;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 
 use anyhow::{anyhow, Context as _, Result};
-use chrono::{DateTime, Datelike, Local, NaiveDate, TimeZone, Utc};
+use chrono::{DateTime, Datelike, Local, NaiveDate, NaiveTime, TimeZone, Utc};
 use clap::ArgMatches;
 use colored::*;
 use comrak::{markdown_to_html, ComrakOptions};
 use reqwest::Client;
 use tokio::fs;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::net::TcpListener;
 use uuid::Uuid;
 
-use crate::config::Config;
-use crate::page::{convert_image_paths_in_text, Page, CURRENT_PAGE_VERSION};
-use crate::storage;
+use crate::backend::{
+    DropboxBackend, LocalBackend, S3Backend, SftpBackend, SyncBackend, WebDavBackend,
+};
+use crate::config::{BackendKind, Config};
+use crate::crypto::{self, Keyring};
+use crate::git;
+use crate::migration;
+use crate::page::{
+    self, convert_image_paths_in_text, Page, Revision, WeekPage, CURRENT_PAGE_VERSION,
+};
+use crate::peer;
+use crate::storage::{self, FsStorage, Storage};
 use crate::{dropbox, dropbox::AccessToken};
 
 #[allow(dead_code)]
 pub struct Context<'a> {
     directory: PathBuf,
+    storage: Box<dyn Storage>,
     config_path: PathBuf,
     config: Config,
     matches: &'a ArgMatches<'a>,
@@ -38,6 +53,7 @@ impl<'a> Context<'a> {
     ) -> Self {
         Self {
             directory: directory.to_path_buf(),
+            storage: Box::new(FsStorage::new(directory)),
             config_path: config_path.to_path_buf(),
             config,
             matches,
@@ -51,9 +67,12 @@ impl<'a> Context<'a> {
 struct ParsedPage {
     title: String,
     text: String,
+    tags: Vec<String>,
     images: Vec<(PathBuf, String)>,
 }
 
+const TAGS_LINE_PREFIX: &str = "tags:";
+
 fn parse_page(text: String, image_prefix: &str) -> Result<ParsedPage> {
     if text.trim().is_empty() {
         return Err(anyhow!("キャンセルされました"));
@@ -70,7 +89,35 @@ fn parse_page(text: String, image_prefix: &str) -> Result<ParsedPage> {
 
     // 本文
     let text: String = iter.collect();
-    let text = text.trim();
+    let mut text = text.trim();
+
+    // 本文の最初の行が "tags:" で始まっていればタグとして取得する
+    let mut tags = Vec::new();
+    if text.starts_with(TAGS_LINE_PREFIX) {
+        let (line, rest) = match text.find('\n') {
+            Some(pos) => (&text[..pos], &text[pos + 1..]),
+            None => (text, ""),
+        };
+
+        tags.extend(
+            line[TAGS_LINE_PREFIX.len()..]
+                .split(|c: char| c == ',' || c.is_whitespace())
+                .map(|tag| tag.trim_start_matches('#'))
+                .filter(|tag| !tag.is_empty())
+                .map(|tag| tag.to_string()),
+        );
+        text = rest.trim();
+    }
+
+    // タイトルと本文中の "#タグ" もタグとして扱う
+    for tag in page::extract_tags(title)
+        .into_iter()
+        .chain(page::extract_tags(text))
+    {
+        if !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
 
     let (text, images) = convert_image_paths_in_text(text, |s| {
         if s.starts_with(image_prefix) {
@@ -83,18 +130,44 @@ fn parse_page(text: String, image_prefix: &str) -> Result<ParsedPage> {
     Ok(ParsedPage {
         title: title.to_string(),
         text: text.to_string(),
+        tags,
         images,
     })
 }
 
-fn generate_image_prefix(created_at: &DateTime<Utc>) -> String {
-    created_at.format("%Y-%m-%d_%H-%M-%S-%f_").to_string()
+// エディタで編集するための文字列を生成する
+fn format_page_for_editing(page: &Page) -> String {
+    // 本文中に "#タグ" として書かれていないタグだけ "tags:" の行に書く
+    let mut inline_tags = page::extract_tags(&page.title);
+    inline_tags.extend(page::extract_tags(&page.text));
+    let tags: Vec<&str> = page
+        .tags
+        .iter()
+        .filter(|tag| !inline_tags.contains(tag))
+        .map(|tag| tag.as_ref())
+        .collect();
+
+    if tags.is_empty() {
+        format!("{}\n\n{}", page.title, page.text)
+    } else {
+        format!(
+            "{}\n{} {}\n\n{}",
+            page.title,
+            TAGS_LINE_PREFIX,
+            tags.join(" "),
+            page.text
+        )
+    }
 }
 
 const TEMP_FILE_TO_EDIT: &str = "new_page.md";
 const AMEND_FILE: &str = "amend_page.md";
 const ACCESS_TOKEN_FILE: &str = "access_token";
+const WEBDAV_PASSWORD_FILE: &str = "webdav_password";
+const S3_SECRET_ACCESS_KEY_FILE: &str = "s3_secret_access_key";
 const FILE_FOR_SHOWING: &str = "show.html";
+// 復号した内容を書き出す一時ディレクトリの名前の接頭辞
+const TEMP_DIR_PREFIX: &str = "diary2-";
 
 const DEFAULT_COMMAND_OPEN: &str = {
     #[cfg(target_os = "windows")]
@@ -131,6 +204,64 @@ fn execute_editor(editor: &str, filepath: &Path) -> Result<bool> {
     Ok(status.success())
 }
 
+// 復号した内容を書き出す一時ディレクトリを作成する。
+// 日記のディレクトリと一緒にバックアップされないように、その外に本人だけが読めるように作る
+fn create_temp_dir() -> Result<PathBuf> {
+    let dir = env::temp_dir().join(format!("{}{}", TEMP_DIR_PREFIX, Uuid::new_v4()));
+
+    let mut builder = std::fs::DirBuilder::new();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    builder.create(&dir).with_context(|| {
+        format!(
+            "一時ディレクトリ `{}` を作成できませんでした",
+            dir.display()
+        )
+    })?;
+
+    Ok(dir)
+}
+
+// 編集した結果を書き込み終えたら一時ディレクトリを削除する。
+// 途中で失敗したときは書いた内容を失わないように残して場所を表示する
+async fn finish_editing<T>(temp_dir: &Path, file_path: &Path, result: Result<T>) -> Result<T> {
+    if result.is_err() {
+        // 空にしてキャンセルされたのなら残す必要はない
+        let text = fs::read_to_string(file_path).await.unwrap_or_default();
+        if !text.trim().is_empty() {
+            eprintln!("編集した内容は `{}` に残してあります", file_path.display());
+            return result;
+        }
+    }
+
+    // 平文を残さない
+    fs::remove_dir_all(temp_dir)
+        .await
+        .with_context(|| format!("`{}` の削除に失敗しました", temp_dir.display()))?;
+
+    result
+}
+
+// 以前のバージョンが日記のディレクトリに残した平文のファイルを削除する
+pub fn remove_plaintext_files(directory: &Path) -> Result<()> {
+    for file_name in &[FILE_FOR_SHOWING, AMEND_FILE] {
+        let path = directory.join(file_name);
+        if path.exists() {
+            std::fs::remove_file(&path)?;
+        }
+    }
+
+    let viewable_image_dir = directory.join(storage::VIEWABLE_IMAGE_DIR);
+    if viewable_image_dir.exists() {
+        std::fs::remove_dir_all(&viewable_image_dir)?;
+    }
+
+    Ok(())
+}
+
 fn open_file_with_associated(file: &Path, command: Option<&str>) -> Result<()> {
     let command = command.unwrap_or(DEFAULT_COMMAND_OPEN);
 
@@ -156,14 +287,40 @@ pub fn config(ctx: Context) -> Result<()> {
     Ok(())
 }
 
-pub fn print_page_headers<I: Iterator<Item = Page>>(iter: I) {
+const SHORT_ID_LEN: usize = 8;
+
+pub fn print_page_headers<I: Iterator<Item = Page>>(iter: I, show_id: bool) {
     for page in iter {
         let local = page.created_at.with_timezone(&Local);
-        println!(
-            "{} {}",
-            page.title,
-            format!("{}", local.format("%Y/%m/%d %H:%M")).yellow()
-        );
+        let date = format!("{}", local.format("%Y/%m/%d %H:%M")).yellow();
+
+        if show_id {
+            let short_id = page.id.get(..SHORT_ID_LEN).unwrap_or(&page.id);
+            println!("{} {} {}", short_id.dimmed(), page.title, date);
+        } else {
+            println!("{} {}", page.title, date);
+        }
+    }
+}
+
+// IDの前方一致でページを1つ探す
+async fn find_page_by_id(storage: &dyn Storage, id: &str) -> Result<Page> {
+    if id.is_empty() {
+        return Err(anyhow!("IDが空です"));
+    }
+
+    let mut pages =
+        storage::list_with_filter(storage, u32::max_value(), |page| page.id.starts_with(id))
+            .await
+            .context("ページの取得に失敗しました")?;
+
+    match pages.len() {
+        0 => Err(anyhow!("ID `{}` のページが見つかりませんでした", id)),
+        1 => Ok(pages.remove(0)),
+        _ => Err(anyhow!(
+            "ID `{}` に一致するページが複数あります。もっと長く指定してください",
+            id
+        )),
     }
 }
 
@@ -179,6 +336,74 @@ fn print_page(page: &Page) {
     println!("{}\n", page.text);
 }
 
+// gitで同期する場合は変更をコミットする
+fn commit_changes(ctx: &Context<'_>, message: &str) -> Result<()> {
+    if ctx.config.sync.backend == BackendKind::Git {
+        git::commit(&ctx.directory, message).context("変更のコミットに失敗しました")?;
+    }
+
+    Ok(())
+}
+
+// --revealが指定されていれば隠しページのパスフレーズを入力させる
+async fn keyring_to_reveal(ctx: &Context<'_>) -> Result<Option<Keyring>> {
+    if !ctx.subcommand_matches.is_present("reveal") {
+        return Ok(None);
+    }
+
+    let keyring = crypto::unlock_hidden(&ctx.directory, false)?;
+
+    let count = legacy_hidden_pages(ctx.storage.as_ref()).await?.len();
+    if count > 0 {
+        eprintln!(
+            "暗号化されていない隠しページが{}個あります。`diary2 hide` で暗号化してください",
+            count
+        );
+    }
+
+    Ok(Some(keyring))
+}
+
+// 以前のバージョンで隠したページは平文のまま保存されている
+async fn legacy_hidden_pages(storage: &dyn Storage) -> Result<Vec<Page>> {
+    storage::list_with_filter(storage, u32::max_value(), |page| {
+        page.hidden && !page.is_sealed()
+    })
+    .await
+    .context("ページの取得に失敗しました")
+}
+
+// 隠しページは表示する場合は復号し、表示しない場合はNoneを返す
+fn reveal(keyring: Option<&mut Keyring>, mut page: Page) -> Result<Option<Page>> {
+    if !page.hidden {
+        return Ok(Some(page));
+    }
+
+    match keyring {
+        Some(keyring) => {
+            page.unseal(keyring)?;
+            Ok(Some(page))
+        }
+        None => Ok(None),
+    }
+}
+
+// 暗号化された隠しページは内容を変更できない
+fn ensure_not_sealed(page: &Page) -> Result<()> {
+    if page.is_sealed() {
+        Err(anyhow!(
+            "隠されたページです。`diary2 unhide` で元に戻してから操作してください"
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+// 指定されたタグをすべて持っているか
+fn has_tags(page: &Page, tags: &[&str]) -> bool {
+    tags.iter().all(|tag| page.tags.iter().any(|t| t == tag))
+}
+
 pub async fn list(ctx: Context<'_>) -> Result<()> {
     let limit = match ctx.subcommand_matches.value_of("limit") {
         Some(limit) => limit
@@ -187,84 +412,150 @@ pub async fn list(ctx: Context<'_>) -> Result<()> {
         None => ctx.config.default_list_limit,
     };
 
-    let pages = storage::list_with_filter(&ctx.directory, limit, |page| !page.hidden)
-        .await
-        .context("ページの取得に失敗しました")?;
+    let tags: Vec<&str> = ctx
+        .subcommand_matches
+        .values_of("tag")
+        .map(|values| values.collect())
+        .unwrap_or_default();
 
-    print_page_headers(pages.into_iter());
+    let mut keyring = keyring_to_reveal(&ctx).await?;
+    let pages = storage::list_with_filter_map(ctx.storage.as_ref(), limit, |page| {
+        Ok(reveal(keyring.as_mut(), page)?.filter(|page| has_tags(page, &tags)))
+    })
+    .await
+    .context("ページの取得に失敗しました")?;
+
+    print_page_headers(pages.into_iter(), ctx.subcommand_matches.is_present("id"));
 
     Ok(())
 }
 
-pub async fn new(ctx: Context<'_>) -> Result<()> {
-    let temp_file_path = ctx.directory.join(TEMP_FILE_TO_EDIT);
+// 指定されなかった方はdefaultの日付と時刻を使う
+fn parse_datetime(
+    date_str: Option<&str>,
+    time_str: Option<&str>,
+    default: DateTime<Local>,
+) -> Result<DateTime<Utc>> {
+    let date = match date_str {
+        Some(s) => parse_date_str(s).context("日付を解析できませんでした")?,
+        None => default.date().naive_local(),
+    };
 
+    let time = match time_str {
+        Some(s) => NaiveTime::parse_from_str(s.trim(), "%H:%M")
+            .context("時刻を解析できませんでした。HH:MMの形式で指定してください")?,
+        None => default.time(),
+    };
+
+    let datetime = Local
+        .from_local_datetime(&date.and_time(time))
+        .earliest()
+        .ok_or_else(|| anyhow!("存在しない日時です"))?;
+
+    Ok(datetime.with_timezone(&Utc))
+}
+
+pub async fn new(ctx: Context<'_>) -> Result<()> {
     // listコマンドで表示するかどうか
     let hidden = ctx.subcommand_matches.is_present("hidden");
-    // エディタを起動する前の時刻を保存
-    let created_at = Utc::now();
+    // --dateか--timeが指定されていればその日時、されていなければエディタを起動する前の時刻を保存
+    let date_str = ctx.subcommand_matches.value_of("date");
+    let time_str = ctx.subcommand_matches.value_of("time");
+    let created_at = if date_str.is_some() || time_str.is_some() {
+        parse_datetime(date_str, time_str, Local::now())?
+    } else {
+        Utc::now()
+    };
 
-    // エディタを起動
-    execute_editor(&ctx.config.editor, &temp_file_path)
-        .context("エディタの起動に失敗しました: {}")?;
+    // 隠しページは暗号化するので、書き終わってから失敗しないように先にパスフレーズを入力させる
+    let mut keyring = if hidden {
+        Some(crypto::unlock_hidden(&ctx.directory, true)?)
+    } else {
+        None
+    };
 
-    // エディタで編集されたファイルを読み込む
-    let text = fs::read_to_string(&temp_file_path).await.with_context(|| {
-        format!(
-            "ファイル `{}` の読み込みに失敗しました",
-            temp_file_path.display()
-        )
-    })?;
+    let temp_dir = create_temp_dir()?;
+    let temp_file_path = temp_dir.join(TEMP_FILE_TO_EDIT);
+    let result: Result<String> = async {
+        // 以前のバージョンが日記のディレクトリに残した書きかけのページがあれば引き継ぐ
+        let draft_path = ctx.directory.join(TEMP_FILE_TO_EDIT);
+        if draft_path.exists() {
+            fs::copy(&draft_path, &temp_file_path)
+                .await
+                .with_context(|| format!("`{}` のコピーに失敗しました", draft_path.display()))?;
+            fs::remove_file(&draft_path)
+                .await
+                .with_context(|| format!("`{}` の削除に失敗しました", draft_path.display()))?;
+        }
 
-    let image_prefix = generate_image_prefix(&created_at);
+        // エディタを起動
+        execute_editor(&ctx.config.editor, &temp_file_path)
+            .context("エディタの起動に失敗しました: {}")?;
 
-    let parsed_page = parse_page(text, &image_prefix).context("ページのパースに失敗しました")?;
+        // エディタで編集されたファイルを読み込む
+        let text = fs::read_to_string(&temp_file_path).await.with_context(|| {
+            format!(
+                "ファイル `{}` の読み込みに失敗しました",
+                temp_file_path.display()
+            )
+        })?;
 
-    let page = Page {
-        id: Uuid::new_v4().to_string(),
-        title: parsed_page.title,
-        text: parsed_page.text,
-        hidden,
-        created_at,
-        updated_at: vec![Utc::now()],
-    };
+        let image_prefix = page::generate_image_prefix(&created_at);
+
+        let parsed_page =
+            parse_page(text, &image_prefix).context("ページのパースに失敗しました")?;
+
+        let mut page = Page {
+            id: Uuid::new_v4().to_string(),
+            title: parsed_page.title,
+            text: parsed_page.text,
+            tags: parsed_page.tags,
+            hidden,
+            created_at,
+            updated_at: vec![Utc::now()],
+            revisions: Vec::new(),
+            sealed: None,
+        };
 
-    // 画像をコピー
-    for (original_path, file_name) in parsed_page.images {
-        if !original_path.exists() {
-            return Err(anyhow!(
-                "`{}` が存在しません",
-                original_path.to_string_lossy()
-            ));
-        } else {
-            storage::write_image(&ctx.directory, &original_path, &file_name)
-                .await
-                .with_context(|| {
-                    format!("`{}` の書き込みに失敗しました", original_path.display())
-                })?;
+        if let Some(keyring) = keyring.as_mut() {
+            page.seal(keyring).context("ページの暗号化に失敗しました")?;
         }
-    }
 
-    // ページを書き込む
-    storage::write(&ctx.directory, page)
-        .await
-        .context("ページの書き込みに失敗しました")?;
+        // 画像をコピー
+        for (original_path, file_name) in parsed_page.images {
+            if !original_path.exists() {
+                return Err(anyhow!(
+                    "`{}` が存在しません",
+                    original_path.to_string_lossy()
+                ));
+            } else {
+                storage::write_image(ctx.storage.as_ref(), &original_path, &file_name)
+                    .await
+                    .with_context(|| {
+                        format!("`{}` の書き込みに失敗しました", original_path.display())
+                    })?;
+            }
+        }
 
-    // 一時ファイルを削除
-    // 書き込みに失敗したときはここまで到達できないので削除されない
-    fs::remove_file(&temp_file_path).await.with_context(|| {
-        format!(
-            "ファイル `{}` の削除に失敗しました",
-            temp_file_path.display()
-        )
-    })?;
+        // ページを書き込む
+        let id = page.id.clone();
+        storage::write(ctx.storage.as_ref(), page)
+            .await
+            .context("ページの書き込みに失敗しました")?;
+
+        Ok(id)
+    }
+    .await;
+
+    let id = finish_editing(&temp_dir, &temp_file_path, result).await?;
+    commit_changes(&ctx, &format!("ページ{}を追加", id))?;
 
     Ok(())
 }
 
 pub async fn lastdt(ctx: Context<'_>) -> Result<()> {
     // 最新のページを取得
-    let pages = storage::list(&ctx.directory, 1)
+    let pages = storage::list(ctx.storage.as_ref(), 1)
         .await
         .context("ページの取得に失敗しました")?;
 
@@ -297,14 +588,13 @@ pub fn parse_date_str(s: &str) -> Option<NaiveDate> {
         };
     }
 
-    let date = match values.len() {
-        1 => NaiveDate::from_ymd(default_year, default_month, values[0]),
-        2 => NaiveDate::from_ymd(default_year, values[0], values[1]),
-        3 => NaiveDate::from_ymd(values[0] as i32, values[1], values[2]),
-        _ => return None,
-    };
-
-    Some(date)
+    // 存在しない日付の場合はNoneを返す
+    match values.len() {
+        1 => NaiveDate::from_ymd_opt(default_year, default_month, values[0]),
+        2 => NaiveDate::from_ymd_opt(default_year, values[0], values[1]),
+        3 => NaiveDate::from_ymd_opt(values[0] as i32, values[1], values[2]),
+        _ => None,
+    }
 }
 
 fn escape(raw: &str) -> Cow<str> {
@@ -336,7 +626,7 @@ fn escape(raw: &str) -> Cow<str> {
     }
 }
 
-fn pages_to_html<I>(directory: &Path, date: NaiveDate, pages: I) -> String
+fn pages_to_html<I>(image_dir: &Path, date: NaiveDate, pages: I) -> String
 where
     I: Iterator<Item = Page>,
 {
@@ -395,7 +685,7 @@ where
     for page in pages {
         // 画像URLを修正
         let (text, _) = convert_image_paths_in_text(&page.text, |s| {
-            let path = directory.join(storage::IMAGE_DIR).join(s);
+            let path = image_dir.join(s);
             format!("{}", path.display())
         });
 
@@ -433,27 +723,60 @@ async fn show_with_browser(directory: &Path, command: Option<&str>, s: &str) ->
 }
 
 async fn show_page_with_browser<I>(
+    storage: &dyn Storage,
     directory: &Path,
     command: Option<&str>,
+    revealed: bool,
     date: NaiveDate,
     pages: I,
 ) -> Result<()>
 where
     I: Iterator<Item = Page>,
 {
-    let html = pages_to_html(directory, date, pages);
-    show_with_browser(directory, command, &html).await?;
+    let pages: Vec<Page> = pages.collect();
+    // 暗号化されている画像はそのままでは表示できない
+    let image_files: Vec<String> = pages
+        .iter()
+        .flat_map(|page| page::image_file_names(&page.text))
+        .collect();
+
+    // 平文を書き出す必要がなければこれまで通り日記のディレクトリに書き出して開くだけにする
+    if !storage.is_encrypted() && !revealed {
+        let image_dir = storage.viewable_image_dir(&image_files, directory).await?;
+        let html = pages_to_html(&image_dir, date, pages.into_iter());
+        return show_with_browser(directory, command, &html).await;
+    }
 
-    Ok(())
-}
+    let temp_dir = create_temp_dir()?;
 
-pub async fn show(ctx: Context<'_>) -> Result<()> {
-    let date_str = ctx.subcommand_matches.value_of("date");
-    let date = match date_str {
-        Some(s) => parse_date_str(s).context("日付を解析できませんでした")?,
-        None => Local::today().naive_local(),
-    };
+    let shown: Result<()> = async {
+        let image_dir = storage.viewable_image_dir(&image_files, &temp_dir).await?;
 
+        let html = pages_to_html(&image_dir, date, pages.into_iter());
+        show_with_browser(&temp_dir, command, &html).await?;
+
+        // ブラウザが読み込み終わるまで残しておく。中断された場合も削除する
+        println!("表示し終えたらEnterを押してください");
+        let mut line = String::new();
+        let mut stdin = BufReader::new(tokio::io::stdin());
+        tokio::select! {
+            _ = stdin.read_line(&mut line) => {}
+            _ = tokio::signal::ctrl_c() => {}
+        }
+
+        Ok(())
+    }
+    .await;
+
+    fs::remove_dir_all(&temp_dir)
+        .await
+        .with_context(|| format!("`{}` の削除に失敗しました", temp_dir.display()))?;
+
+    shown
+}
+
+// 指定された日付に作成されたページを取得する
+async fn get_pages_on_date(storage: &dyn Storage, date: NaiveDate) -> Result<Vec<Page>> {
     // 日付をUTCに変換
     let datetime = date.and_hms(0, 0, 0);
     let datetime = Local
@@ -462,30 +785,37 @@ pub async fn show(ctx: Context<'_>) -> Result<()> {
         .with_timezone(&Utc);
 
     // 指定された日付の週のページを取得
-    let week_pages = storage::get_week_page_range(
-        &ctx.directory,
-        &datetime,
-        &(datetime + chrono::Duration::days(1)),
-    )
-    .await
-    .context("ページの取得に失敗しました")?;
+    let week_pages =
+        storage::get_week_page_range(storage, &datetime, &(datetime + chrono::Duration::days(1)))
+            .await
+            .context("ページの取得に失敗しました")?;
+
+    let mut pages: Vec<Page> = week_pages
+        .into_iter()
+        .flat_map(|week_page| week_page.pages)
+        .filter(|page| page.created_at.with_timezone(&Local).date().naive_local() == date)
+        .collect();
+    pages.sort_by_key(|page| page.created_at);
+
+    Ok(pages)
+}
 
-    let mut pages: Box<dyn Iterator<Item = Page>> = Box::new(iter::empty());
-
-    for week_page in week_pages {
-        // 指定された日付のページだけ抽出
-        pages = Box::new(
-            pages.chain(
-                week_page
-                    .pages
-                    .into_iter()
-                    .filter(|page| !page.hidden)
-                    .filter(|page| {
-                        page.created_at.with_timezone(&Local).date().naive_local() == date
-                    }),
-            ),
-        );
+pub async fn show(ctx: Context<'_>) -> Result<()> {
+    let date_str = ctx.subcommand_matches.value_of("date");
+    let date = match date_str {
+        Some(s) => parse_date_str(s).context("日付を解析できませんでした")?,
+        None => Local::today().naive_local(),
+    };
+
+    // 指定された日付のページだけ抽出
+    let mut keyring = keyring_to_reveal(&ctx).await?;
+    let mut pages = Vec::new();
+    for page in get_pages_on_date(ctx.storage.as_ref(), date).await? {
+        if let Some(page) = reveal(keyring.as_mut(), page)? {
+            pages.push(page);
+        }
     }
+    let mut pages = pages.into_iter();
 
     if ctx.subcommand_matches.is_present("stdout") {
         if let Some(first_page) = pages.next() {
@@ -498,8 +828,10 @@ pub async fn show(ctx: Context<'_>) -> Result<()> {
         }
     } else {
         show_page_with_browser(
+            ctx.storage.as_ref(),
             &ctx.directory,
             ctx.config.browser.as_ref().map(|s| s.as_ref()),
+            keyring.is_some(),
             date,
             pages,
         )
@@ -509,72 +841,517 @@ pub async fn show(ctx: Context<'_>) -> Result<()> {
     Ok(())
 }
 
+// 一時ファイルをエディタで編集してページを更新する
+async fn edit_page(ctx: &Context<'_>, mut page: Page) -> Result<()> {
+    ensure_not_sealed(&page)?;
+
+    // 以前のバージョンで隠したページは、平文のまま書き戻さずに暗号化する
+    let mut keyring = if page.hidden {
+        Some(crypto::unlock_hidden(&ctx.directory, true)?)
+    } else {
+        None
+    };
+
+    // 一時ファイルへ書き込む
+    let temp_dir = create_temp_dir()?;
+    let amend_file_path = temp_dir.join(AMEND_FILE);
+    let result: Result<String> = async {
+        let content = format_page_for_editing(&page);
+        fs::write(&amend_file_path, &content)
+            .await
+            .with_context(|| {
+                format!(
+                    "一時ファイル `{}` への書き込みに失敗しました",
+                    amend_file_path.display()
+                )
+            })?;
+
+        // エディタを開く
+        execute_editor(&ctx.config.editor, &amend_file_path)
+            .context("エディタの起動に失敗しました: {}")?;
+
+        // エディタで編集されたファイルを読み込む
+        let text = fs::read_to_string(&amend_file_path)
+            .await
+            .with_context(|| {
+                format!(
+                    "一時ファイル `{}` の読み込みに失敗しました",
+                    amend_file_path.display()
+                )
+            })?;
+
+        // ページをパース
+        let image_prefix = page::generate_image_prefix(&page.created_at);
+        let parsed_page =
+            parse_page(text, &image_prefix).context("ページのパースに失敗しました")?;
+
+        // 内容が変わっていれば以前の内容を履歴に残す
+        if page.title != parsed_page.title
+            || page.text != parsed_page.text
+            || page.tags != parsed_page.tags
+        {
+            page.revisions.push(page.current_revision());
+        }
+
+        page.title = parsed_page.title;
+        page.text = parsed_page.text;
+        page.tags = parsed_page.tags;
+        page.updated_at.push(Utc::now());
+
+        if let Some(keyring) = keyring.as_mut() {
+            page.seal(keyring).context("ページの暗号化に失敗しました")?;
+        }
+
+        // 画像を書き込む
+        for (original_path, file_name) in parsed_page.images {
+            if original_path.exists() {
+                storage::write_image(ctx.storage.as_ref(), &original_path, &file_name)
+                    .await
+                    .with_context(|| {
+                        format!("`{}` の書き込みに失敗しました", original_path.display())
+                    })?;
+            } else {
+                eprintln!(
+                    "`{}` が存在しなかったため無視しました",
+                    original_path.to_string_lossy()
+                );
+            }
+        }
+
+        // ページを書き込む
+        let id = page.id.clone();
+        storage::write(ctx.storage.as_ref(), page)
+            .await
+            .context("ページの書き込みに失敗しました")?;
+
+        Ok(id)
+    }
+    .await;
+
+    let id = finish_editing(&temp_dir, &amend_file_path, result).await?;
+    commit_changes(ctx, &format!("ページ{}を編集", id))?;
+
+    Ok(())
+}
+
 pub async fn amend(ctx: Context<'_>) -> Result<()> {
     // 最新のページを取得
-    let pages = storage::list(&ctx.directory, 1)
+    let pages = storage::list(ctx.storage.as_ref(), 1)
         .await
         .context("ページの取得に失敗しました")?;
 
-    let mut last_page = match pages.into_iter().next() {
+    let last_page = match pages.into_iter().next() {
         Some(page) => page,
         None => return Err(anyhow!("ページがありません")),
     };
 
-    // 一時ファイルへ書き込む
-    let amend_file_path = ctx.directory.join(AMEND_FILE);
-    let content = format!("{}\n\n{}", last_page.title, last_page.text);
-    fs::write(&amend_file_path, &content)
+    edit_page(&ctx, last_page).await
+}
+
+// 番号付きの一覧を表示してページを選択させる
+fn select_page(mut pages: Vec<Page>) -> Result<Page> {
+    for (i, page) in pages.iter().enumerate() {
+        let local = page.created_at.with_timezone(&Local);
+        println!(
+            "{}: {} {}",
+            i + 1,
+            page.title,
+            format!("{}", local.format("%Y/%m/%d %H:%M")).yellow()
+        );
+    }
+
+    print!("番号を入力してください: ");
+    io::stdout().flush()?;
+
+    let mut input = String::new();
+    io::stdin().read_line(&mut input)?;
+
+    match input.trim().parse::<usize>() {
+        Ok(n) if n >= 1 && n <= pages.len() => Ok(pages.remove(n - 1)),
+        _ => Err(anyhow!("番号が正しくありません")),
+    }
+}
+
+pub async fn edit(ctx: Context<'_>) -> Result<()> {
+    let target = ctx.subcommand_matches.value_of("target").unwrap();
+
+    // 日付として解釈できればその日のページ、できなければIDの前方一致で探す
+    let pages = match parse_date_str(target) {
+        Some(date) => get_pages_on_date(ctx.storage.as_ref(), date)
+            .await?
+            .into_iter()
+            .filter(|page| !page.hidden)
+            .collect(),
+        None => Vec::new(),
+    };
+
+    let page = match pages.len() {
+        0 => find_page_by_id(ctx.storage.as_ref(), target).await?,
+        1 => pages.into_iter().next().unwrap(),
+        _ => select_page(pages)?,
+    };
+
+    edit_page(&ctx, page).await
+}
+
+pub async fn delete(ctx: Context<'_>) -> Result<()> {
+    let id = ctx.subcommand_matches.value_of("id").unwrap();
+    let page = find_page_by_id(ctx.storage.as_ref(), id).await?;
+
+    storage::delete(ctx.storage.as_ref(), &page.id)
         .await
-        .with_context(|| {
-            format!(
-                "一時ファイル `{}` への書き込みに失敗しました",
-                amend_file_path.display()
-            )
-        })?;
+        .context("ページの削除に失敗しました")?;
 
-    // エディタを開く
-    execute_editor(&ctx.config.editor, &amend_file_path)
-        .context("エディタの起動に失敗しました: {}")?;
+    println!("「{}」をゴミ箱に移動しました", page.title);
 
-    // エディタで編集されたファイルを読み込む
-    let text = fs::read_to_string(&amend_file_path)
+    Ok(())
+}
+
+pub async fn retime(ctx: Context<'_>) -> Result<()> {
+    let id = ctx.subcommand_matches.value_of("id").unwrap();
+    let datetime_str = ctx.subcommand_matches.value_of("datetime").unwrap();
+
+    let page = find_page_by_id(ctx.storage.as_ref(), id).await?;
+
+    // "日付 [時刻]" の形式で指定する。時刻を省略した場合は元の時刻のまま
+    let mut values = datetime_str.split_whitespace();
+    let created_at = parse_datetime(
+        values.next(),
+        values.next(),
+        page.created_at.with_timezone(&Local),
+    )?;
+
+    let page = storage::retime(ctx.storage.as_ref(), &page.id, created_at)
         .await
-        .with_context(|| {
-            format!(
-                "一時ファイル `{}` の読み込みに失敗しました",
-                amend_file_path.display()
-            )
-        })?;
+        .context("ページの移動に失敗しました")?;
 
-    // ページをパース
-    let image_prefix = generate_image_prefix(&last_page.created_at);
-    let parsed_page = parse_page(text, &image_prefix).context("ページのパースに失敗しました")?;
+    println!(
+        "「{}」を{}に移動しました",
+        page.title,
+        page.created_at
+            .with_timezone(&Local)
+            .format("%Y/%m/%d %H:%M")
+    );
 
-    last_page.title = parsed_page.title;
-    last_page.text = parsed_page.text;
-    last_page.updated_at.push(Utc::now());
+    Ok(())
+}
 
-    // 画像を書き込む
-    for (original_path, file_name) in parsed_page.images {
-        if original_path.exists() {
-            storage::write_image(&ctx.directory, &original_path, &file_name)
-                .await
-                .with_context(|| {
-                    format!("`{}` の書き込みに失敗しました", original_path.display())
-                })?;
-        } else {
-            eprintln!(
-                "`{}` が存在しなかったため無視しました",
-                original_path.to_string_lossy()
-            );
+pub async fn hide(ctx: Context<'_>) -> Result<()> {
+    let page = match ctx.subcommand_matches.value_of("id") {
+        Some(id) => {
+            let page = find_page_by_id(ctx.storage.as_ref(), id).await?;
+            if page.is_sealed() {
+                return Err(anyhow!("既に隠されています"));
+            }
+            Some(page)
         }
+        None => None,
+    };
+
+    // 暗号化する前は隠しページでも平文で保存されている
+    let mut keyring = crypto::unlock_hidden(&ctx.directory, true)?;
+
+    if let Some(mut page) = page {
+        let title = page.title.clone();
+        page.seal(&mut keyring)
+            .context("ページの暗号化に失敗しました")?;
+        page.hidden = true;
+        page.updated_at.push(Utc::now());
+
+        storage::write(ctx.storage.as_ref(), page)
+            .await
+            .context("ページの書き込みに失敗しました")?;
+
+        println!("「{}」を隠しました", title);
+    }
+
+    // 以前のバージョンで隠したページもまとめて暗号化する
+    let pages = legacy_hidden_pages(ctx.storage.as_ref()).await?;
+    let count = pages.len();
+    for mut page in pages {
+        page.seal(&mut keyring)
+            .context("ページの暗号化に失敗しました")?;
+        page.updated_at.push(Utc::now());
+
+        storage::write(ctx.storage.as_ref(), page)
+            .await
+            .context("ページの書き込みに失敗しました")?;
     }
+    if count > 0 {
+        println!(
+            "暗号化されていなかった隠しページを{}個暗号化しました",
+            count
+        );
+    }
+
+    Ok(())
+}
+
+pub async fn unhide(ctx: Context<'_>) -> Result<()> {
+    let id = ctx.subcommand_matches.value_of("id").unwrap();
+    let mut page = find_page_by_id(ctx.storage.as_ref(), id).await?;
 
-    // ページを書き込む
-    storage::write(&ctx.directory, last_page)
+    if !page.hidden {
+        return Err(anyhow!("隠されていません"));
+    }
+
+    if page.is_sealed() {
+        let mut keyring = crypto::unlock_hidden(&ctx.directory, false)?;
+        page.unseal(&mut keyring)?;
+    }
+    page.hidden = false;
+    page.updated_at.push(Utc::now());
+
+    let title = page.title.clone();
+    storage::write(ctx.storage.as_ref(), page)
+        .await
+        .context("ページの書き込みに失敗しました")?;
+
+    println!("「{}」を表示するようにしました", title);
+
+    Ok(())
+}
+
+pub async fn history(ctx: Context<'_>) -> Result<()> {
+    let id = ctx.subcommand_matches.value_of("id").unwrap();
+    let page = find_page_by_id(ctx.storage.as_ref(), id).await?;
+    ensure_not_sealed(&page)?;
+
+    let current = page.current_revision_number();
+    for number in 1..=current {
+        let revision = page.revision(number).unwrap();
+        let saved_at = revision.saved_at.with_timezone(&Local);
+
+        println!(
+            "{} {} {}{}",
+            format!("{:>3}", number).dimmed(),
+            revision.title,
+            format!("{}", saved_at.format("%Y/%m/%d %H:%M")).yellow(),
+            if number == current { " (現在)" } else { "" }
+        );
+    }
+
+    Ok(())
+}
+
+// 比較するための文字列に変換する
+fn revision_to_string(revision: &Revision) -> String {
+    if revision.tags.is_empty() {
+        format!("{}\n\n{}", revision.title, revision.text)
+    } else {
+        format!(
+            "{}\n{} {}\n\n{}",
+            revision.title,
+            TAGS_LINE_PREFIX,
+            revision.tags.join(" "),
+            revision.text
+        )
+    }
+}
+
+fn print_line_diff(old: &str, new: &str) {
+    for result in diff::lines(old, new) {
+        match result {
+            diff::Result::Left(line) => println!("{}", format!("-{}", line).red()),
+            diff::Result::Right(line) => println!("{}", format!("+{}", line).green()),
+            diff::Result::Both(line, _) => println!(" {}", line),
+        }
+    }
+}
+
+// 日本語は単語の間に空白がないので文字単位で比較する
+fn print_char_diff(old: &str, new: &str) {
+    let mut output = String::new();
+    let mut removed = String::new();
+    let mut added = String::new();
+
+    // 連続して削除・追加された文字をまとめて色付けする
+    let flush = |output: &mut String, removed: &mut String, added: &mut String| {
+        if !removed.is_empty() {
+            output.push_str(&format!("{}", removed.as_str().red().strikethrough()));
+            removed.clear();
+        }
+        if !added.is_empty() {
+            output.push_str(&format!("{}", added.as_str().green().underline()));
+            added.clear();
+        }
+    };
+
+    for result in diff::chars(old, new) {
+        match result {
+            diff::Result::Left(ch) => removed.push(ch),
+            diff::Result::Right(ch) => added.push(ch),
+            diff::Result::Both(ch, _) => {
+                flush(&mut output, &mut removed, &mut added);
+                output.push(ch);
+            }
+        }
+    }
+    flush(&mut output, &mut removed, &mut added);
+
+    println!("{}", output);
+}
+
+fn parse_revision_number(page: &Page, s: Option<&str>, default: usize) -> Result<Revision> {
+    let number = match s {
+        Some(s) => s
+            .parse::<usize>()
+            .with_context(|| format!("版の番号 `{}` が数値ではありません", s))?,
+        None => default,
+    };
+
+    page.revision(number).ok_or_else(|| {
+        anyhow!(
+            "版{}は存在しません。1から{}までの番号を指定してください",
+            number,
+            page.current_revision_number()
+        )
+    })
+}
+
+pub async fn diff(ctx: Context<'_>) -> Result<()> {
+    let id = ctx.subcommand_matches.value_of("id").unwrap();
+    let page = find_page_by_id(ctx.storage.as_ref(), id).await?;
+    ensure_not_sealed(&page)?;
+
+    // 省略した場合は1つ前の版と現在の内容を比較する
+    let current = page.current_revision_number();
+    let old = parse_revision_number(
+        &page,
+        ctx.subcommand_matches.value_of("rev1"),
+        current.saturating_sub(1).max(1),
+    )?;
+    let new = parse_revision_number(&page, ctx.subcommand_matches.value_of("rev2"), current)?;
+
+    let format_saved_at = |revision: &Revision| {
+        format!(
+            "{}",
+            revision
+                .saved_at
+                .with_timezone(&Local)
+                .format("%Y/%m/%d %H:%M")
+        )
+    };
+    println!("{}", format!("--- {}", format_saved_at(&old)).red());
+    println!("{}", format!("+++ {}", format_saved_at(&new)).green());
+
+    let old = revision_to_string(&old);
+    let new = revision_to_string(&new);
+    if ctx.subcommand_matches.is_present("char") {
+        print_char_diff(&old, &new);
+    } else {
+        print_line_diff(&old, &new);
+    }
+
+    Ok(())
+}
+
+pub async fn revert(ctx: Context<'_>) -> Result<()> {
+    let id = ctx.subcommand_matches.value_of("id").unwrap();
+    let mut page = find_page_by_id(ctx.storage.as_ref(), id).await?;
+    ensure_not_sealed(&page)?;
+
+    let revision = parse_revision_number(&page, ctx.subcommand_matches.value_of("rev"), 0)?;
+    if revision.title == page.title && revision.text == page.text && revision.tags == page.tags {
+        println!("既に現在の内容です");
+        return Ok(());
+    }
+
+    // 現在の内容も履歴に残してから戻す
+    page.revisions.push(page.current_revision());
+    page.title = revision.title;
+    page.text = revision.text;
+    page.tags = revision.tags;
+    page.updated_at.push(Utc::now());
+
+    // その版で使われていた画像を戻す
+    let missing = storage::restore_images(ctx.storage.as_ref(), &page.text)
+        .await
+        .context("画像の復元に失敗しました")?;
+    for file_name in missing {
+        eprintln!("画像 `{}` が見つかりませんでした", file_name);
+    }
+
+    let title = page.title.clone();
+    storage::write(ctx.storage.as_ref(), page)
         .await
         .context("ページの書き込みに失敗しました")?;
 
+    println!(
+        "「{}」を版{}の内容に戻しました",
+        title,
+        ctx.subcommand_matches.value_of("rev").unwrap()
+    );
+
+    Ok(())
+}
+
+pub async fn trash(ctx: Context<'_>) -> Result<()> {
+    match ctx.subcommand_matches.subcommand() {
+        ("list", _) => {
+            let trashed_pages = storage::list_trash(ctx.storage.as_ref())
+                .await
+                .context("ゴミ箱のページの取得に失敗しました")?;
+
+            for trashed_page in trashed_pages {
+                let page = &trashed_page.page;
+                let short_id = page.id.get(..SHORT_ID_LEN).unwrap_or(&page.id);
+                let created_at = page.created_at.with_timezone(&Local);
+                let deleted_at = trashed_page.deleted_at.with_timezone(&Local);
+                println!(
+                    "{} {} {} {}",
+                    short_id.dimmed(),
+                    page.title,
+                    format!("{}", created_at.format("%Y/%m/%d %H:%M")).yellow(),
+                    format!("(削除: {})", deleted_at.format("%Y/%m/%d %H:%M")).red()
+                );
+            }
+        }
+        ("restore", Some(matches)) => {
+            let id = matches.value_of("id").unwrap();
+
+            let trashed_pages = storage::list_trash(ctx.storage.as_ref())
+                .await
+                .context("ゴミ箱のページの取得に失敗しました")?;
+            let mut found: Vec<_> = trashed_pages
+                .into_iter()
+                .filter(|trashed_page| trashed_page.page.id.starts_with(id))
+                .collect();
+
+            let trashed_page = match found.len() {
+                0 => return Err(anyhow!("ID `{}` のページはゴミ箱にありません", id)),
+                1 => found.remove(0),
+                _ => {
+                    return Err(anyhow!(
+                        "ID `{}` に一致するページが複数あります。もっと長く指定してください",
+                        id
+                    ))
+                }
+            };
+
+            let page = storage::restore_from_trash(ctx.storage.as_ref(), &trashed_page.page.id)
+                .await
+                .context("ページの復元に失敗しました")?;
+
+            println!("「{}」を復元しました", page.title);
+        }
+        ("empty", Some(matches)) => {
+            // --allが指定されていなければ保持期間を過ぎたページだけ削除する
+            let before = if matches.is_present("all") {
+                Utc::now()
+            } else {
+                Utc::now() - chrono::Duration::days(ctx.config.trash_retention_days as i64)
+            };
+
+            let count = storage::empty_trash(ctx.storage.as_ref(), before)
+                .await
+                .context("ゴミ箱を空にできませんでした")?;
+
+            println!("{}件のページを完全に削除しました", count);
+        }
+        _ => unreachable!(),
+    };
+
     Ok(())
 }
 
@@ -598,9 +1375,15 @@ pub async fn search(ctx: Context<'_>) -> Result<()> {
         }
     };
 
+    let tags: Vec<&str> = ctx
+        .subcommand_matches
+        .values_of("tag")
+        .map(|values| values.collect())
+        .unwrap_or_default();
+
     // オプションを元にクロージャを生成
     let filter = |page: &Page| -> bool {
-        if page.hidden {
+        if !has_tags(page, &tags) {
             return false;
         }
 
@@ -613,10 +1396,13 @@ pub async fn search(ctx: Context<'_>) -> Result<()> {
         }
     };
 
-    // 検索
-    let pages = storage::list_with_filter(&ctx.directory, limit, filter)
-        .await
-        .context("ページの取得に失敗しました")?;
+    // 検索。隠しページは--revealが指定されていれば復号してから検索する
+    let mut keyring = keyring_to_reveal(&ctx).await?;
+    let pages = storage::list_with_filter_map(ctx.storage.as_ref(), limit, |page| {
+        Ok(reveal(keyring.as_mut(), page)?.filter(|page| filter(page)))
+    })
+    .await
+    .context("ページの取得に失敗しました")?;
 
     if should_show_first_page {
         if !pages.is_empty() {
@@ -625,8 +1411,10 @@ pub async fn search(ctx: Context<'_>) -> Result<()> {
             } else {
                 // 表示
                 show_page_with_browser(
+                    ctx.storage.as_ref(),
                     &ctx.directory,
                     ctx.config.browser.as_ref().map(|s| s.as_ref()),
+                    keyring.is_some(),
                     pages[0]
                         .created_at
                         .with_timezone(&Local)
@@ -640,7 +1428,32 @@ pub async fn search(ctx: Context<'_>) -> Result<()> {
             eprintln!("ページが見つかりませんでした");
         }
     } else {
-        print_page_headers(pages.into_iter());
+        print_page_headers(pages.into_iter(), ctx.subcommand_matches.is_present("id"));
+    }
+
+    Ok(())
+}
+
+pub async fn tags(ctx: Context<'_>) -> Result<()> {
+    let pages =
+        storage::list_with_filter(ctx.storage.as_ref(), u32::max_value(), |page| !page.hidden)
+            .await
+            .context("ページの取得に失敗しました")?;
+
+    // タグごとにページ数を数える
+    let mut counts: HashMap<String, u32> = HashMap::new();
+    for page in pages {
+        for tag in page.tags {
+            *counts.entry(tag).or_insert(0) += 1;
+        }
+    }
+
+    // ページ数の降順、同じ数ならタグの昇順で表示する
+    let mut counts: Vec<(String, u32)> = counts.into_iter().collect();
+    counts.sort_by(|(tag1, count1), (tag2, count2)| count2.cmp(count1).then(tag1.cmp(tag2)));
+
+    for (tag, count) in counts {
+        println!("{} {}", tag, format!("({})", count).yellow());
     }
 
     Ok(())
@@ -661,38 +1474,253 @@ pub async fn auth(ctx: Context<'_>) -> Result<()> {
     Ok(())
 }
 
+// 設定に従って同期先を作成する
+async fn create_backend(ctx: &Context<'_>) -> Result<Box<dyn SyncBackend>> {
+    match ctx.config.sync.backend {
+        BackendKind::Dropbox => {
+            // アクセストークンを取得
+            let path = ctx.directory.join(ACCESS_TOKEN_FILE);
+            if !path.exists() {
+                println!("認証していません。");
+                println!("`diary2 auth` を実行して認証してください。");
+            }
+
+            let access_token = fs::read_to_string(path)
+                .await
+                .context("アクセストークンの取得に失敗しました: {}")?;
+
+            let access_token = AccessToken {
+                value: access_token,
+            };
+
+            Ok(Box::new(DropboxBackend::new(Client::new(), access_token)))
+        }
+        BackendKind::Local => {
+            let local = ctx
+                .config
+                .sync
+                .local
+                .as_ref()
+                .ok_or_else(|| anyhow!("[sync.local]に同期先のpathを設定してください"))?;
+
+            Ok(Box::new(LocalBackend::new(&local.path)))
+        }
+        BackendKind::WebDav => {
+            let webdav = ctx
+                .config
+                .sync
+                .webdav
+                .as_ref()
+                .ok_or_else(|| anyhow!("[sync.webdav]に同期先のurlを設定してください"))?;
+
+            // パスワードを設定ファイルに書きたくない場合はファイルに保存しておく
+            let password = match &webdav.password {
+                Some(password) => Some(password.clone()),
+                None => {
+                    let path = ctx.directory.join(WEBDAV_PASSWORD_FILE);
+                    if path.exists() {
+                        let password = fs::read_to_string(path)
+                            .await
+                            .context("WebDAVのパスワードの取得に失敗しました")?;
+                        Some(password.trim_end().to_string())
+                    } else {
+                        None
+                    }
+                }
+            };
+
+            Ok(Box::new(WebDavBackend::new(
+                Client::new(),
+                &webdav.url,
+                webdav.username.clone(),
+                password,
+            )))
+        }
+        BackendKind::S3 => {
+            let s3 = ctx
+                .config
+                .sync
+                .s3
+                .as_ref()
+                .ok_or_else(|| anyhow!("[sync.s3]に同期先のバケットを設定してください"))?;
+
+            let secret_access_key = match &s3.secret_access_key {
+                Some(secret_access_key) => secret_access_key.clone(),
+                None => {
+                    let path = ctx.directory.join(S3_SECRET_ACCESS_KEY_FILE);
+                    let secret_access_key = fs::read_to_string(path)
+                        .await
+                        .context("S3のシークレットアクセスキーの取得に失敗しました")?;
+                    secret_access_key.trim_end().to_string()
+                }
+            };
+
+            Ok(Box::new(S3Backend::new(
+                Client::new(),
+                s3,
+                secret_access_key,
+            )))
+        }
+        BackendKind::Sftp => {
+            let sftp = ctx
+                .config
+                .sync
+                .sftp
+                .as_ref()
+                .ok_or_else(|| anyhow!("[sync.sftp]に同期先のhostとpathを設定してください"))?;
+
+            Ok(Box::new(SftpBackend::new(sftp.clone())))
+        }
+        BackendKind::Git => Err(anyhow!("gitで同期する場合は同期先を作成しません")),
+    }
+}
+
+fn sync_with_git(ctx: &Context<'_>) -> Result<()> {
+    if ctx.config.sync.encrypt {
+        return Err(anyhow!("gitで同期する場合は暗号化できません"));
+    }
+    // 暗号化されたファイルはマージできない
+    if ctx.storage.is_encrypted() {
+        return Err(anyhow!("日記を暗号化している場合はgitで同期できません"));
+    }
+
+    let config = ctx
+        .config
+        .sync
+        .git
+        .as_ref()
+        .ok_or_else(|| anyhow!("[sync.git]に同期先のremoteを設定してください"))?;
+
+    git::sync(&ctx.directory, config)
+}
+
+// 設定された合言葉
+fn peer_secret(ctx: &Context<'_>) -> Option<String> {
+    ctx.config
+        .sync
+        .peer
+        .as_ref()
+        .and_then(|peer| peer.secret.clone())
+}
+
+async fn sync_with_peer(ctx: &Context<'_>, peer: &str) -> Result<()> {
+    // ポート番号は省略できる
+    let addr = if peer.contains(':') {
+        peer.to_string()
+    } else {
+        format!("{}:{}", peer, peer::DEFAULT_PORT)
+    };
+
+    let secret = match peer_secret(ctx) {
+        Some(secret) => secret,
+        None => crypto::read_passphrase(peer::CODE_ENV, "相手の端末に表示されたコード: ")?
+            .trim()
+            .to_uppercase(),
+    };
+
+    peer::sync(ctx.storage.as_ref(), &addr, &secret).await
+}
+
+// 同期したときに行うことを表示する
+async fn print_sync_plan(ctx: &Context<'_>, backend: &dyn SyncBackend) -> Result<()> {
+    let plan = storage::plan_sync(ctx.storage.as_ref(), backend, ctx.config.sync.encrypt).await?;
+
+    if plan.pages.is_empty() && plan.images.is_empty() {
+        println!("同期するファイルはありません");
+        return Ok(());
+    }
+
+    for (file_name, action) in plan.pages.iter().chain(plan.images.iter()) {
+        match action {
+            storage::SyncAction::Download => println!("{}をダウンロードします", file_name),
+            storage::SyncAction::Upload => println!("{}をアップロードします", file_name),
+            storage::SyncAction::Merge {
+                added_to_local,
+                added_to_remote,
+                conflicts,
+            } => {
+                println!("{}を統合します", file_name);
+                if !added_to_local.is_empty() {
+                    println!(
+                        "  ローカルに追加されるページ: {}",
+                        added_to_local.join(", ")
+                    );
+                }
+                if !added_to_remote.is_empty() {
+                    println!(
+                        "  リモートに追加されるページ: {}",
+                        added_to_remote.join(", ")
+                    );
+                }
+                if !conflicts.is_empty() {
+                    println!("  両方で編集されたページ: {}", conflicts.join(", "));
+                }
+            }
+            storage::SyncAction::Skip => println!("{}は同期しません", file_name),
+        }
+    }
+
+    println!("--dry-runが指定されているため同期しませんでした");
+
+    Ok(())
+}
+
 pub async fn sync(ctx: Context<'_>) -> Result<()> {
-    // バックアップを取っておく
-    let backup_id = storage::create_pages_backup(&ctx.directory)
-        .await
-        .context("バックアップの作成に失敗しました")?;
+    let peer = ctx.subcommand_matches.value_of("peer");
+    let dry_run = ctx.subcommand_matches.is_present("dry-run");
+
+    // 他の端末と直接同期する場合とgitで同期する場合は同期先を作成しない。
+    // gitで同期する場合は更新済みリストを使わずにマージする
+    let backend = match ctx.config.sync.backend {
+        _ if peer.is_some() => None,
+        BackendKind::Git => None,
+        _ => Some(create_backend(&ctx).await?),
+    };
 
-    // アクセストークンを取得
-    let path = ctx.directory.join(ACCESS_TOKEN_FILE);
-    if !path.exists() {
-        println!("認証していません。");
-        println!("`diary2 auth` を実行して認証してください。");
+    if dry_run {
+        return match &backend {
+            Some(backend) => print_sync_plan(&ctx, backend.as_ref()).await,
+            None => Err(anyhow!(
+                "--dry-runはgitや他の端末と同期する場合には使えません"
+            )),
+        };
     }
 
-    let access_token = fs::read_to_string(path)
+    // 同期先とはファイルごとに同期し終えたことを記録するので、失敗しても元に戻さずに次回続きから同期する
+    if let (None, Some(backend)) = (peer, &backend) {
+        return storage::sync(
+            ctx.storage.as_ref(),
+            backend.as_ref(),
+            ctx.config.sync.encrypt,
+        )
         .await
-        .context("アクセストークンの取得に失敗しました: {}")?;
+        .context("同期に失敗しました。もう一度実行すると続きから同期します");
+    }
+
+    // バックアップを取っておく
+    let backup_id = ctx
+        .storage
+        .create_pages_backup()
+        .await
+        .context("バックアップの作成に失敗しました")?;
 
-    let access_token = AccessToken {
-        value: access_token,
+    let result = match peer {
+        Some(peer) => sync_with_peer(&ctx, peer).await,
+        None => sync_with_git(&ctx),
     };
 
-    let client = Client::new();
-    match storage::sync(&ctx.directory, &client, &access_token).await {
+    match result {
         Ok(_) => {
             // バックアップを削除
-            storage::remove_pages_backup(&ctx.directory, backup_id)
+            ctx.storage
+                .remove_pages_backup(backup_id)
                 .await
                 .context("バックアップの削除に失敗しました")?;
         }
         Err(err) => {
             // バックアップを復元する
-            storage::rollback(&ctx.directory, backup_id)
+            ctx.storage
+                .rollback(backup_id)
                 .await
                 .context("バックアップの復元に失敗しました")?;
 
@@ -703,19 +1731,166 @@ pub async fn sync(ctx: Context<'_>) -> Result<()> {
     Ok(())
 }
 
-pub async fn fixpage(ctx: Context<'_>) -> Result<()> {
-    match (ctx.page_version, CURRENT_PAGE_VERSION) {
-        (a, b) if a == b => {
-            println!("変換は必要ありません");
-        }
-        (1, 2) => {
-            storage::fix_1_to_2(&ctx.directory)
-                .await
-                .context("修正に失敗しました: {}")?;
+// `diary2 sync --peer`で接続してくる端末を待ち受ける
+pub async fn serve_sync(ctx: Context<'_>) -> Result<()> {
+    let default_addr = format!("0.0.0.0:{}", peer::DEFAULT_PORT);
+    let addr = ctx
+        .subcommand_matches
+        .value_of("bind")
+        .unwrap_or(&default_addr);
+
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("{}で待ち受けられませんでした", addr))?;
+
+    // 合言葉を設定していなければ、一度だけ使えるコードを表示する
+    let (secret, once) = match peer_secret(&ctx) {
+        Some(secret) => (secret, false),
+        None => {
+            let code = peer::generate_code();
+            println!("相手の端末で次のコードを入力してください: {}", code);
+            (code, true)
         }
-        _ => unreachable!(),
     };
 
+    println!("{}で待ち受けています...", listener.local_addr()?);
+
+    peer::serve(ctx.storage.as_ref(), listener, &secret, once).await
+}
+
+// 週ごとのファイルを読み込む。暗号化されていれば復号する
+async fn read_week_file(storage: &dyn Storage, path: &str) -> Result<WeekPage> {
+    let data = fs::read(path)
+        .await
+        .with_context(|| format!("{}の読み込みに失敗しました", path))?;
+    let json = String::from_utf8(storage.decrypt_if_needed(data)?)?;
+
+    migration::parse_week_page(&json).with_context(|| format!("{}を読み込めませんでした", path))
+}
+
+// gitのマージドライバー。同期と同じように統合した結果をoursに書き込む
+pub async fn merge_driver(ctx: Context<'_>) -> Result<()> {
+    let base_path = ctx.subcommand_matches.value_of("base").unwrap();
+    let ours_path = ctx.subcommand_matches.value_of("ours").unwrap();
+    let theirs_path = ctx.subcommand_matches.value_of("theirs").unwrap();
+
+    // 共通の祖先にファイルがなければ空のファイルが渡される
+    let base = if fs::metadata(base_path).await?.len() > 0 {
+        Some(read_week_file(ctx.storage.as_ref(), base_path).await?)
+    } else {
+        None
+    };
+    let ours = read_week_file(ctx.storage.as_ref(), ours_path).await?;
+    let theirs = read_week_file(ctx.storage.as_ref(), theirs_path).await?;
+
+    let (wpage, _) = storage::merge(ours, theirs, base.as_ref());
+    let json = serde_json::to_string(&wpage)?;
+    fs::write(ours_path, ctx.storage.encrypt_if_enabled(json.as_bytes())?)
+        .await
+        .with_context(|| format!("{}の書き込みに失敗しました", ours_path))?;
+
+    Ok(())
+}
+
+pub async fn encrypt(ctx: Context<'_>) -> Result<()> {
+    // gitの履歴には平文が残り続け、鍵ファイルは.gitignoreで除外されるので他の端末では読めなくなる
+    if ctx.directory.join(".git").exists() {
+        return Err(anyhow!(
+            "`{}` はgitで管理されているため暗号化できません。履歴に暗号化する前の内容が残ります",
+            ctx.directory.display()
+        ));
+    }
+
+    // 途中で失敗した場合は、鍵ファイルがあるので残りのファイルだけを暗号化する
+    if !ctx.storage.is_encrypted() {
+        let passphrase =
+            crypto::read_new_passphrase(crypto::PASSPHRASE_ENV, "新しいパスフレーズ: ")?;
+        ctx.storage
+            .enable_encryption(&passphrase)
+            .context("暗号化の設定に失敗しました")?;
+    }
+
+    let count = ctx
+        .storage
+        .encrypt_all()
+        .await
+        .context("暗号化に失敗しました")?;
+
+    println!("{}個のファイルを暗号化しました", count);
+    println!("パスフレーズを忘れると日記を読めなくなるので注意してください");
+
+    Ok(())
+}
+
+pub async fn decrypt(ctx: Context<'_>) -> Result<()> {
+    if !ctx.storage.is_encrypted() {
+        return Err(anyhow!("暗号化されていません"));
+    }
+
+    let count = ctx
+        .storage
+        .decrypt_all()
+        .await
+        .context("復号に失敗しました")?;
+
+    // すべて復号してから鍵ファイルを削除する
+    ctx.storage.disable_encryption()?;
+
+    println!("{}個のファイルを復号しました", count);
+
+    Ok(())
+}
+
+pub async fn fixpage(ctx: Context<'_>) -> Result<()> {
+    let dry_run = ctx.subcommand_matches.is_present("dry-run");
+
+    if ctx.page_version == CURRENT_PAGE_VERSION {
+        println!("変換は必要ありません");
+        return Ok(());
+    }
+
+    for (from, description) in migration::descriptions(ctx.page_version)? {
+        println!("バージョン{} -> {}: {}", from, from + 1, description);
+    }
+
+    // 書き込む前にすべてのファイルを変換して検証する
+    let converted = migration::prepare(ctx.storage.as_ref(), ctx.page_version)
+        .await
+        .context("修正に失敗しました")?;
+    println!("{}個のファイルを検証しました", converted.len());
+
+    if dry_run {
+        println!("--dry-runが指定されているため書き込みませんでした");
+        return Ok(());
+    }
+
+    // バックアップを取っておく
+    let backup_id = ctx
+        .storage
+        .create_pages_backup()
+        .await
+        .context("バックアップの作成に失敗しました")?;
+
+    if let Err(err) = migration::apply(ctx.storage.as_ref(), converted).await {
+        // バックアップを復元する
+        ctx.storage
+            .rollback(backup_id)
+            .await
+            .context("バックアップの復元に失敗しました")?;
+
+        return Err(err).context("修正に失敗しました");
+    }
+
+    migration::write_page_version(&ctx.directory, CURRENT_PAGE_VERSION)
+        .await
+        .context("バージョンの書き込みに失敗しました")?;
+
+    println!(
+        "バージョン{}に変換しました。変換前のページは `{}` にあります",
+        CURRENT_PAGE_VERSION,
+        ctx.storage.pages_backup_location(backup_id)
+    );
+
     Ok(())
 }
 
@@ -732,4 +1907,72 @@ mod tests {
         );
         assert_eq!("html", escape("html").to_string());
     }
+
+    #[test]
+    fn test_parse_page_with_tags() {
+        let text = String::from("タイトル #日記\ntags: 旅行, #写真\n\n京都に行った #旅行 #食事\n");
+        let parsed_page = parse_page(text, "prefix_").unwrap();
+
+        assert_eq!("タイトル #日記", parsed_page.title);
+        assert_eq!("京都に行った #旅行 #食事", parsed_page.text);
+        assert_eq!(vec!["旅行", "写真", "日記", "食事"], parsed_page.tags);
+    }
+
+    #[test]
+    fn test_format_page_for_editing() {
+        let page = Page {
+            id: String::from("a"),
+            title: String::from("タイトル"),
+            text: String::from("本文 #旅行"),
+            tags: vec![String::from("旅行"), String::from("写真")],
+            hidden: false,
+            created_at: Utc::now(),
+            updated_at: Vec::new(),
+            revisions: Vec::new(),
+            sealed: None,
+        };
+
+        let content = format_page_for_editing(&page);
+        assert_eq!("タイトル\ntags: 写真\n\n本文 #旅行", content);
+
+        // 編集せずに保存した場合はタグが変わらない
+        let parsed_page = parse_page(content, "prefix_").unwrap();
+        assert_eq!(vec!["写真", "旅行"], parsed_page.tags);
+    }
+
+    #[test]
+    fn test_parse_date_str() {
+        assert_eq!(
+            Some(NaiveDate::from_ymd(2020, 4, 12)),
+            parse_date_str("2020/4/12")
+        );
+        assert_eq!(
+            Some(NaiveDate::from_ymd(2020, 4, 12)),
+            parse_date_str("2020-04-12")
+        );
+        assert_eq!(None, parse_date_str("2020/2/30"));
+        assert_eq!(None, parse_date_str("3f2a"));
+        assert_eq!(None, parse_date_str(""));
+    }
+
+    #[test]
+    fn test_parse_datetime() {
+        let default = Local.ymd(2020, 4, 1).and_hms(8, 15, 0);
+
+        let datetime = parse_datetime(Some("2020/4/12"), Some("23:30"), default).unwrap();
+        assert_eq!(
+            NaiveDate::from_ymd(2020, 4, 12).and_hms(23, 30, 0),
+            datetime.with_timezone(&Local).naive_local()
+        );
+
+        // 指定されなかった時刻はdefaultのものを使う
+        let datetime = parse_datetime(Some("2020/4/12"), None, default).unwrap();
+        assert_eq!(
+            NaiveDate::from_ymd(2020, 4, 12).and_hms(8, 15, 0),
+            datetime.with_timezone(&Local).naive_local()
+        );
+
+        assert!(parse_datetime(None, Some("25:00"), default).is_err());
+        assert!(parse_datetime(Some("2020/2/30"), None, default).is_err());
+    }
 }
@@ -1,8 +1,19 @@
+use std::path::PathBuf;
+
 #[derive(Debug, Deserialize)]
 pub struct Config {
     pub editor: String,
     pub browser: Option<String>,
     pub default_list_limit: u32,
+    // ゴミ箱のページを保持する日数
+    #[serde(default = "default_trash_retention_days")]
+    pub trash_retention_days: u32,
+    #[serde(default)]
+    pub sync: SyncConfig,
+}
+
+fn default_trash_retention_days() -> u32 {
+    30
 }
 
 impl Config {
@@ -11,6 +22,150 @@ impl Config {
             editor: String::from("vim"),
             browser: None,
             default_list_limit: 7,
+            trash_retention_days: default_trash_retention_days(),
+            sync: SyncConfig::default(),
         }
     }
 }
+
+// 同期先の種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendKind {
+    Dropbox,
+    // マウントされたディレクトリ
+    Local,
+    WebDav,
+    // S3互換のストレージ
+    S3,
+    // pagesとimagesをgitのリポジトリにする
+    Git,
+    Sftp,
+}
+
+impl Default for BackendKind {
+    fn default() -> Self {
+        BackendKind::Dropbox
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct SyncConfig {
+    #[serde(default)]
+    pub backend: BackendKind,
+    // 暗号化して同期する
+    #[serde(default)]
+    pub encrypt: bool,
+    pub local: Option<LocalConfig>,
+    pub webdav: Option<WebDavConfig>,
+    pub s3: Option<S3Config>,
+    pub git: Option<GitConfig>,
+    pub sftp: Option<SftpConfig>,
+    pub peer: Option<PeerConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LocalConfig {
+    // 同期先のディレクトリ
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WebDavConfig {
+    // 同期先のフォルダのURL
+    pub url: String,
+    pub username: Option<String>,
+    // 設定しなければwebdav_passwordファイルから読み込む
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct S3Config {
+    // 例: https://s3.ap-northeast-1.amazonaws.com, http://127.0.0.1:9000
+    pub endpoint: String,
+    pub bucket: String,
+    #[serde(default = "default_s3_region")]
+    pub region: String,
+    // バケット内のフォルダ
+    #[serde(default)]
+    pub prefix: String,
+    pub access_key_id: String,
+    // 設定しなければs3_secret_access_keyファイルから読み込む
+    pub secret_access_key: Option<String>,
+}
+
+fn default_s3_region() -> String {
+    String::from("us-east-1")
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GitConfig {
+    // プッシュするリポジトリのURLかパス
+    pub remote: String,
+    #[serde(default = "default_git_branch")]
+    pub branch: String,
+}
+
+fn default_git_branch() -> String {
+    String::from("master")
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SftpConfig {
+    pub host: String,
+    #[serde(default = "default_sftp_port")]
+    pub port: u16,
+    pub username: String,
+    // 秘密鍵のパス。設定しなければssh-agentを使う
+    pub private_key: Option<PathBuf>,
+    // 設定しなければ~/.ssh/known_hostsを使う
+    pub known_hosts: Option<PathBuf>,
+    // 同期先のディレクトリ
+    pub path: String,
+}
+
+fn default_sftp_port() -> u16 {
+    22
+}
+
+// `diary2 sync --peer`と`diary2 serve-sync`で使う
+#[derive(Debug, Deserialize)]
+pub struct PeerConfig {
+    // 端末間で共有する合言葉。設定しなければ待ち受けるたびに使い捨てのコードを表示する
+    pub secret: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sync_config() {
+        let config: Config = toml::from_str(
+            r#"
+editor = "vim"
+default_list_limit = 7
+
+[sync]
+backend = "local"
+encrypt = true
+
+[sync.local]
+path = "/mnt/share/diary"
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(config.sync.backend, BackendKind::Local);
+        assert!(config.sync.encrypt);
+        assert_eq!(
+            config.sync.local.unwrap().path,
+            PathBuf::from("/mnt/share/diary")
+        );
+
+        // [sync]がなければDropboxに同期する
+        let config: Config = toml::from_str("editor = \"vim\"\ndefault_list_limit = 7").unwrap();
+        assert_eq!(config.sync.backend, BackendKind::Dropbox);
+        assert!(!config.sync.encrypt);
+    }
+}
@@ -0,0 +1,440 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, Context as _, Result};
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use hmac::{Hmac, Mac, NewMac};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::Sha256;
+
+pub const KEY_FILE: &str = "encryption.json";
+pub const PASSPHRASE_ENV: &str = "DIARY2_PASSPHRASE";
+pub const SYNC_PASSPHRASE_ENV: &str = "DIARY2_SYNC_PASSPHRASE";
+pub const HIDDEN_KEY_FILE: &str = "hidden_key.json";
+pub const HIDDEN_PASSPHRASE_ENV: &str = "DIARY2_HIDDEN_PASSPHRASE";
+
+// 暗号化されたデータの先頭につける
+const MAGIC: &[u8] = b"DIARY2ENC1";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const KEY_LEN: usize = 32;
+// パスフレーズが正しいか確かめるために暗号化しておく文字列
+const CHECK_TEXT: &[u8] = b"diary2";
+
+#[derive(Clone)]
+pub struct Key([u8; KEY_LEN]);
+
+// パスフレーズから鍵を導出するための情報。鍵そのものは含まない
+#[derive(Debug, Serialize, Deserialize)]
+pub struct KeyFile {
+    salt: String,
+    check: String,
+}
+
+impl KeyFile {
+    pub fn new(passphrase: &str) -> Result<(Self, Key)> {
+        let salt = random_bytes(SALT_LEN);
+        let key = derive_key(passphrase, &salt)?;
+        let check = encrypt(&key, CHECK_TEXT)?;
+
+        let key_file = Self {
+            salt: base64::encode(&salt),
+            check: base64::encode(&check),
+        };
+
+        Ok((key_file, key))
+    }
+
+    pub fn unlock(&self, passphrase: &str) -> Result<Key> {
+        let salt = base64::decode(&self.salt)?;
+        let key = derive_key(passphrase, &salt)?;
+
+        let check = base64::decode(&self.check)?;
+        match decrypt(&key, &check) {
+            Ok(ref text) if text.as_slice() == CHECK_TEXT => Ok(key),
+            _ => Err(anyhow!("パスフレーズが違います")),
+        }
+    }
+
+    pub fn salt(&self) -> Result<Vec<u8>> {
+        Ok(base64::decode(&self.salt)?)
+    }
+}
+
+pub fn random_bytes(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0u8; len];
+    OsRng.fill_bytes(&mut bytes);
+    bytes
+}
+
+// Argon2でパスフレーズから鍵を導出する
+pub fn derive_key(passphrase: &str, salt: &[u8]) -> Result<Key> {
+    let config = argon2::Config {
+        variant: argon2::Variant::Argon2id,
+        mem_cost: 19456,
+        time_cost: 2,
+        hash_length: KEY_LEN as u32,
+        ..argon2::Config::default()
+    };
+
+    let hash = argon2::hash_raw(passphrase.as_bytes(), salt, &config)?;
+
+    let mut key = [0u8; KEY_LEN];
+    key.copy_from_slice(&hash);
+    Ok(Key(key))
+}
+
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+// ChaCha20-Poly1305で暗号化する。形式は MAGIC + ナンス + 暗号文
+pub fn encrypt(key: &Key, plaintext: &[u8]) -> Result<Vec<u8>> {
+    let cipher = ChaCha20Poly1305::new(chacha20poly1305::Key::from_slice(&key.0));
+    let nonce = random_bytes(NONCE_LEN);
+
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce), plaintext)
+        .map_err(|_| anyhow!("暗号化に失敗しました"))?;
+
+    let mut data = Vec::with_capacity(MAGIC.len() + NONCE_LEN + ciphertext.len());
+    data.extend_from_slice(MAGIC);
+    data.extend_from_slice(&nonce);
+    data.extend_from_slice(&ciphertext);
+
+    Ok(data)
+}
+
+pub fn decrypt(key: &Key, data: &[u8]) -> Result<Vec<u8>> {
+    if !is_encrypted(data) || data.len() < MAGIC.len() + NONCE_LEN {
+        return Err(anyhow!("暗号化されたデータではありません"));
+    }
+
+    let cipher = ChaCha20Poly1305::new(chacha20poly1305::Key::from_slice(&key.0));
+    let (nonce, ciphertext) = data[MAGIC.len()..].split_at(NONCE_LEN);
+
+    cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| anyhow!("復号に失敗しました。鍵が違うかデータが壊れています"))
+}
+
+// 環境変数が設定されていればそれを使い、なければ端末から入力させる
+pub fn read_passphrase(env_name: &str, prompt: &str) -> Result<String> {
+    if let Ok(passphrase) = env::var(env_name) {
+        return Ok(passphrase);
+    }
+
+    let passphrase = rpassword::read_password_from_tty(Some(prompt))
+        .context("パスフレーズの入力に失敗しました")?;
+    Ok(passphrase)
+}
+
+// 新しいパスフレーズを確認のため2回入力させる
+pub fn read_new_passphrase(env_name: &str, prompt: &str) -> Result<String> {
+    if let Ok(passphrase) = env::var(env_name) {
+        return Ok(passphrase);
+    }
+
+    let passphrase = rpassword::read_password_from_tty(Some(prompt))
+        .context("パスフレーズの入力に失敗しました")?;
+    if passphrase.is_empty() {
+        return Err(anyhow!("パスフレーズが空です"));
+    }
+
+    let confirmation = rpassword::read_password_from_tty(Some("もう一度入力してください: "))
+        .context("パスフレーズの入力に失敗しました")?;
+    if passphrase != confirmation {
+        return Err(anyhow!("パスフレーズが一致しません"));
+    }
+
+    Ok(passphrase)
+}
+
+// ==============================
+// 保存されるファイルの暗号化
+// ==============================
+
+pub fn is_enabled(directory: &Path) -> bool {
+    directory.join(KEY_FILE).exists()
+}
+
+// パスフレーズを入力させて鍵を取得する
+pub fn unlock(directory: &Path) -> Result<Key> {
+    let json = fs::read_to_string(directory.join(KEY_FILE))
+        .context("鍵ファイルの読み込みに失敗しました")?;
+    let key_file: KeyFile = serde_json::from_str(&json)?;
+
+    let passphrase = read_passphrase(PASSPHRASE_ENV, "パスフレーズ: ")?;
+    key_file.unlock(&passphrase)
+}
+
+// 鍵ファイルを作成して暗号化を有効にする
+pub fn enable(directory: &Path, passphrase: &str) -> Result<Key> {
+    let (key_file, key) = KeyFile::new(passphrase)?;
+
+    let json = serde_json::to_string(&key_file)?;
+    fs::write(directory.join(KEY_FILE), json).context("鍵ファイルの書き込みに失敗しました")?;
+
+    Ok(key)
+}
+
+pub fn disable(directory: &Path) -> Result<()> {
+    fs::remove_file(directory.join(KEY_FILE)).context("鍵ファイルの削除に失敗しました")?;
+    Ok(())
+}
+
+// ==============================
+// 同期するファイルの暗号化
+// ==============================
+
+// ファイル名からリモートでのファイル名を生成する。同じ鍵なら常に同じ名前になる
+pub fn obfuscate_file_name(key: &Key, file_name: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_varkey(&key.0).unwrap();
+    mac.update(b"file-name:");
+    mac.update(file_name.as_bytes());
+
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// ダウンロードしたときにファイル名がわかるように、ファイル名と内容をまとめて暗号化する
+pub fn encrypt_with_file_name(key: &Key, file_name: &str, contents: &[u8]) -> Result<Vec<u8>> {
+    let mut plaintext = Vec::with_capacity(file_name.len() + 1 + contents.len());
+    plaintext.extend_from_slice(file_name.as_bytes());
+    plaintext.push(b'\n');
+    plaintext.extend_from_slice(contents);
+
+    encrypt(key, &plaintext)
+}
+
+pub fn decrypt_with_file_name(key: &Key, data: &[u8]) -> Result<(String, Vec<u8>)> {
+    let mut plaintext = decrypt(key, data)?;
+
+    let pos = plaintext
+        .iter()
+        .position(|&b| b == b'\n')
+        .ok_or_else(|| anyhow!("ファイル名がありません"))?;
+    let contents = plaintext.split_off(pos + 1);
+    plaintext.pop();
+
+    let file_name = String::from_utf8(plaintext)?;
+    Ok((file_name, contents))
+}
+
+// ==============================
+// 端末間の同期
+// ==============================
+
+// 合言葉とサーバーのナンスから認証用の鍵を導出する。
+// 通信を盗聴されても短いコードを総当たりしにくいようにArgon2を使う
+pub fn derive_peer_key(secret: &str, server_nonce: &[u8]) -> Result<Key> {
+    derive_key(secret, server_nonce)
+}
+
+fn peer_hmac(key: &Key, label: &[u8], server_nonce: &[u8], client_nonce: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_varkey(&key.0).unwrap();
+    mac.update(label);
+    mac.update(b":");
+    mac.update(server_nonce);
+    mac.update(client_nonce);
+    mac
+}
+
+// 合言葉を知っていることを示す値。labelでサーバーとクライアントのどちらの値かを区別する
+pub fn peer_mac(key: &Key, label: &[u8], server_nonce: &[u8], client_nonce: &[u8]) -> Vec<u8> {
+    peer_hmac(key, label, server_nonce, client_nonce)
+        .finalize()
+        .into_bytes()
+        .to_vec()
+}
+
+pub fn verify_peer_mac(
+    key: &Key,
+    label: &[u8],
+    server_nonce: &[u8],
+    client_nonce: &[u8],
+    mac: &[u8],
+) -> bool {
+    peer_hmac(key, label, server_nonce, client_nonce)
+        .verify(mac)
+        .is_ok()
+}
+
+// 認証した後の通信を暗号化する鍵。接続ごとに変わる
+pub fn peer_session_key(key: &Key, server_nonce: &[u8], client_nonce: &[u8]) -> Key {
+    let mut session_key = [0u8; KEY_LEN];
+    session_key.copy_from_slice(&peer_mac(key, b"session", server_nonce, client_nonce));
+    Key(session_key)
+}
+
+// ==============================
+// 隠しページの暗号化
+// ==============================
+
+// 隠しページを暗号化するためのパスフレーズと、それから導出した鍵。
+// 別の端末で暗号化されたページはソルトが違うので、ソルトごとに鍵を導出する
+pub struct Keyring {
+    passphrase: String,
+    // 暗号化するときに使うソルト
+    salt: Option<Vec<u8>>,
+    keys: HashMap<Vec<u8>, Key>,
+}
+
+impl Keyring {
+    pub fn new(passphrase: String) -> Self {
+        Self {
+            passphrase,
+            salt: None,
+            keys: HashMap::new(),
+        }
+    }
+
+    fn with_key(passphrase: String, salt: Vec<u8>, key: Key) -> Self {
+        let mut keyring = Self::new(passphrase);
+        keyring.keys.insert(salt.clone(), key);
+        keyring.salt = Some(salt);
+        keyring
+    }
+
+    fn key(&mut self, salt: &[u8]) -> Result<&Key> {
+        if !self.keys.contains_key(salt) {
+            let key = derive_key(&self.passphrase, salt)?;
+            self.keys.insert(salt.to_vec(), key);
+        }
+
+        Ok(&self.keys[salt])
+    }
+
+    // ソルトを先頭につけて暗号化し、文字列として返す
+    pub fn seal(&mut self, plaintext: &[u8]) -> Result<String> {
+        let salt = self
+            .salt
+            .clone()
+            .ok_or_else(|| anyhow!("隠しページの鍵が作成されていません"))?;
+        let data = encrypt(self.key(&salt)?, plaintext)?;
+
+        let mut sealed = salt;
+        sealed.extend_from_slice(&data);
+        Ok(base64::encode(&sealed))
+    }
+
+    pub fn open(&mut self, sealed: &str) -> Result<Vec<u8>> {
+        let sealed = base64::decode(sealed)?;
+        if sealed.len() < SALT_LEN {
+            return Err(anyhow!("暗号化されたデータが壊れています"));
+        }
+
+        let (salt, data) = sealed.split_at(SALT_LEN);
+        decrypt(self.key(salt)?, data)
+            .map_err(|_| anyhow!("隠しページを復号できません。パスフレーズが違います"))
+    }
+}
+
+// 隠しページのパスフレーズを入力させる。
+// createがtrueで鍵ファイルがなければ、新しいパスフレーズを入力させて作成する
+pub fn unlock_hidden(directory: &Path, create: bool) -> Result<Keyring> {
+    let path = directory.join(HIDDEN_KEY_FILE);
+
+    if path.exists() {
+        let json = fs::read_to_string(&path).context("鍵ファイルの読み込みに失敗しました")?;
+        let key_file: KeyFile = serde_json::from_str(&json)?;
+
+        let passphrase = read_passphrase(HIDDEN_PASSPHRASE_ENV, "隠しページのパスフレーズ: ")?;
+        let key = key_file.unlock(&passphrase)?;
+        Ok(Keyring::with_key(passphrase, key_file.salt()?, key))
+    } else if create {
+        let passphrase =
+            read_new_passphrase(HIDDEN_PASSPHRASE_ENV, "隠しページの新しいパスフレーズ: ")?;
+        let (key_file, key) = KeyFile::new(&passphrase)?;
+
+        let json = serde_json::to_string(&key_file)?;
+        fs::write(&path, json).context("鍵ファイルの書き込みに失敗しました")?;
+
+        Ok(Keyring::with_key(passphrase, key_file.salt()?, key))
+    } else {
+        // 別の端末で隠したページは、同じパスフレーズなら鍵ファイルがなくても復号できる
+        let passphrase = read_passphrase(HIDDEN_PASSPHRASE_ENV, "隠しページのパスフレーズ: ")?;
+        Ok(Keyring::new(passphrase))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encrypt_and_decrypt() {
+        let key = Key([1; KEY_LEN]);
+        let plaintext = "今日の日記".as_bytes();
+
+        let data = encrypt(&key, plaintext).unwrap();
+        assert!(is_encrypted(&data));
+        assert_eq!(plaintext, decrypt(&key, &data).unwrap().as_slice());
+
+        // 同じ内容でも毎回違う暗号文になる
+        assert_ne!(data, encrypt(&key, plaintext).unwrap());
+
+        // 鍵が違うと復号できない
+        assert!(decrypt(&Key([2; KEY_LEN]), &data).is_err());
+
+        // 改ざんされていると復号できない
+        let mut tampered = data.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(decrypt(&key, &tampered).is_err());
+    }
+
+    #[test]
+    fn test_encrypt_with_file_name() {
+        let key = Key([1; KEY_LEN]);
+
+        let data = encrypt_with_file_name(&key, "2020-03-29-2020-04-04.json", b"{}").unwrap();
+        let (file_name, contents) = decrypt_with_file_name(&key, &data).unwrap();
+        assert_eq!("2020-03-29-2020-04-04.json", file_name);
+        assert_eq!(b"{}", contents.as_slice());
+
+        let name = obfuscate_file_name(&key, "2020-03-29-2020-04-04.json");
+        assert_eq!(
+            name,
+            obfuscate_file_name(&key, "2020-03-29-2020-04-04.json")
+        );
+        assert_ne!(
+            name,
+            obfuscate_file_name(&key, "2020-04-05-2020-04-11.json")
+        );
+        assert!(!name.contains("2020"));
+    }
+
+    #[test]
+    fn test_keyring() {
+        let (key_file, key) = KeyFile::new("passphrase").unwrap();
+        let mut keyring =
+            Keyring::with_key(String::from("passphrase"), key_file.salt().unwrap(), key);
+
+        let sealed = keyring.seal(b"hidden").unwrap();
+        assert_eq!(b"hidden", keyring.open(&sealed).unwrap().as_slice());
+
+        // 鍵ファイルがない端末でも同じパスフレーズなら復号できる
+        let mut other = Keyring::new(String::from("passphrase"));
+        assert_eq!(b"hidden", other.open(&sealed).unwrap().as_slice());
+        assert!(other.seal(b"hidden").is_err());
+
+        assert!(Keyring::new(String::from("wrong")).open(&sealed).is_err());
+    }
+
+    #[test]
+    fn test_key_file() {
+        let (key_file, key) = KeyFile::new("passphrase").unwrap();
+
+        let unlocked = key_file.unlock("passphrase").unwrap();
+        assert_eq!(key.0, unlocked.0);
+
+        assert!(key_file.unlock("wrong").is_err());
+    }
+}
@@ -1,21 +1,36 @@
 use std::collections::HashMap;
+use std::future::Future;
+use std::path::Path;
+use std::time::Duration;
 
-use anyhow::Result;
+use anyhow::{anyhow, Context as _, Result};
 use chrono::{DateTime, Utc};
 use oauth2::reqwest::http_client;
 use oauth2::{
     basic::BasicClient, AuthUrl, AuthorizationCode, ClientId, ClientSecret, CsrfToken, RedirectUrl,
     TokenResponse, TokenUrl,
 };
-use reqwest::{header, Client};
+use reqwest::{header, Client, StatusCode};
+use sha2::{Digest, Sha256};
+use tokio::fs::{self, File};
 use tokio::io::BufReader;
 use tokio::net::TcpListener;
 use tokio::prelude::*;
 use tokio::stream::StreamExt;
+use tokio::time;
 use url::Url;
 
 use crate::secret;
 
+// content_hashを計算するときのブロックの大きさ
+const CONTENT_HASH_BLOCK_SIZE: usize = 4 * 1024 * 1024;
+// これより大きいファイルはアップロードセッションで分割して送る。/files/uploadは150MBまで
+const UPLOAD_SESSION_THRESHOLD: usize = 8 * 1024 * 1024;
+// アップロードセッションで1回に送る大きさ。4MBの倍数にする
+const UPLOAD_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+// 失敗したリクエストを繰り返す回数
+const MAX_RETRIES: u32 = 3;
+
 pub struct AccessToken {
     pub value: String,
 }
@@ -101,6 +116,23 @@ pub async fn get_access_token() -> Result<AccessToken> {
 pub struct FileInfo {
     pub name: String,
     pub client_modified: DateTime<Utc>,
+    pub rev: String,
+    pub content_hash: Option<String>,
+}
+
+// Dropboxと同じ方法で内容のハッシュを計算する。
+// 4MBごとのブロックのSHA-256を連結して、さらにSHA-256を計算する
+pub fn content_hash(contents: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    for block in contents.chunks(CONTENT_HASH_BLOCK_SIZE) {
+        hasher.update(Sha256::digest(block));
+    }
+
+    hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
 }
 
 pub async fn download_file_to_string(
@@ -112,11 +144,11 @@ pub async fn download_file_to_string(
     Ok((info, String::from_utf8_lossy(&bytes).to_string()))
 }
 
-pub async fn download_file(
+async fn request_download(
     client: &Client,
     access_token: &AccessToken,
     path: &str,
-) -> Result<(FileInfo, Vec<u8>)> {
+) -> Result<(FileInfo, reqwest::Response)> {
     let mut parameters = HashMap::new();
     parameters.insert("path", path);
     let json = serde_json::to_string(&parameters)?;
@@ -129,21 +161,103 @@ pub async fn download_file(
         )
         .header("Dropbox-API-Arg", &json)
         .send()
-        .await?;
+        .await?
+        .error_for_status()?;
+
+    let info: FileInfo = serde_json::from_str(
+        res.headers()
+            .get("Dropbox-API-Result")
+            .ok_or_else(|| anyhow!("{}の情報を取得できませんでした", path))?
+            .to_str()?,
+    )?;
 
-    let info: FileInfo =
-        serde_json::from_str(res.headers().get("Dropbox-API-Result").unwrap().to_str()?)?;
+    Ok((info, res))
+}
+
+pub async fn download_file(
+    client: &Client,
+    access_token: &AccessToken,
+    path: &str,
+) -> Result<(FileInfo, Vec<u8>)> {
+    let (info, res) = request_download(client, access_token, path).await?;
     let bytes: Vec<u8> = res.bytes().await?.into_iter().collect();
 
     Ok((info, bytes))
 }
 
-pub async fn upload_file<B: Into<reqwest::Body>>(
+// メモリに溜めずに受け取った分からファイルに書き込む。
+// 途中で失敗しても書きかけのファイルを残さないように、一時ファイルに書き込んでから置き換える
+pub async fn download_file_to(
+    client: &Client,
+    access_token: &AccessToken,
+    path: &str,
+    dest: &Path,
+) -> Result<FileInfo> {
+    let (info, mut res) = request_download(client, access_token, path).await?;
+
+    let mut temp_file_name = dest.file_name().unwrap().to_os_string();
+    temp_file_name.push(".tmp");
+    let temp_path = dest.with_file_name(temp_file_name);
+
+    let written: Result<()> = async {
+        let mut file = File::create(&temp_path).await?;
+        while let Some(chunk) = res.chunk().await? {
+            file.write_all(&chunk).await?;
+        }
+        file.sync_all().await?;
+        Ok(())
+    }
+    .await;
+
+    if let Err(err) = written {
+        let _ = fs::remove_file(&temp_path).await;
+        return Err(err).with_context(|| format!("{}のダウンロードに失敗しました", path));
+    }
+    fs::rename(&temp_path, dest).await?;
+
+    Ok(info)
+}
+
+// 繰り返せば成功するかもしれないエラーか。通信の失敗と、429や5xxの応答だけを繰り返す
+fn is_retryable(err: &anyhow::Error) -> bool {
+    match err.downcast_ref::<reqwest::Error>() {
+        Some(err) => match err.status() {
+            Some(status) => status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error(),
+            None => err.is_timeout() || err.is_connect() || err.is_request() || err.is_body(),
+        },
+        None => false,
+    }
+}
+
+// 失敗したら少しずつ間隔を空けて繰り返す
+async fn retry<T, F, Fut>(mut request: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut attempt = 0;
+    loop {
+        match request().await {
+            Ok(value) => return Ok(value),
+            Err(err) if attempt < MAX_RETRIES && is_retryable(&err) => {
+                attempt += 1;
+                time::delay_for(Duration::from_secs(1 << attempt)).await;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+pub async fn upload_file(
     client: &Client,
     access_token: &AccessToken,
     path: &str,
-    contents: B,
+    contents: Vec<u8>,
 ) -> Result<FileInfo> {
+    if contents.len() > UPLOAD_SESSION_THRESHOLD {
+        return upload_file_in_chunks(client, access_token, path, &contents).await;
+    }
+
     let mut parameters = HashMap::new();
     parameters.insert("path", path);
     parameters.insert("mode", "overwrite");
@@ -160,26 +274,322 @@ pub async fn upload_file<B: Into<reqwest::Body>>(
         .body(contents)
         .send()
         .await?
+        .error_for_status()?
         .json()
         .await?;
 
     Ok(info)
 }
 
+// ファイルが存在しない場合だけアップロードする。既に存在すればNoneを返す
+pub async fn upload_new_file(
+    client: &Client,
+    access_token: &AccessToken,
+    path: &str,
+    contents: Vec<u8>,
+) -> Result<Option<FileInfo>> {
+    let mut parameters = HashMap::new();
+    parameters.insert("path", path);
+    parameters.insert("mode", "add");
+    let json = serde_json::to_string(&parameters)?;
+
+    let res = client
+        .post("https://content.dropboxapi.com/2/files/upload")
+        .header(
+            header::AUTHORIZATION,
+            &format!("Bearer {}", &access_token.value),
+        )
+        .header(header::CONTENT_TYPE, "application/octet-stream")
+        .header("Dropbox-API-Arg", &json)
+        .body(contents)
+        .send()
+        .await?;
+
+    // 既に存在するパスは409 (path/conflict) になる
+    if res.status() == StatusCode::CONFLICT {
+        let error = res.text().await?;
+        return match serde_json::from_str::<ErrorResponse>(&error) {
+            Ok(res) if res.error_summary.starts_with("path/conflict") => Ok(None),
+            _ => Err(anyhow!("{}をアップロードできませんでした: {}", path, error)),
+        };
+    }
+
+    Ok(Some(res.error_for_status()?.json().await?))
+}
+
+#[derive(Debug, Serialize)]
+struct UploadSessionCursor<'a> {
+    session_id: &'a str,
+    offset: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct CommitInfo<'a> {
+    path: &'a str,
+    mode: &'a str,
+}
+
+#[derive(Debug, Deserialize)]
+struct UploadSessionStartResult {
+    session_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct UploadSessionError {
+    error: UploadSessionLookupError,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = ".tag", rename_all = "snake_case")]
+enum UploadSessionLookupError {
+    IncorrectOffset {
+        correct_offset: u64,
+    },
+    #[serde(other)]
+    Other,
+}
+
+// アップロードセッションの操作を送る
+async fn post_upload_session<A: serde::Serialize>(
+    client: &Client,
+    access_token: &AccessToken,
+    endpoint: &str,
+    arg: &A,
+    chunk: &[u8],
+) -> Result<reqwest::Response> {
+    let res = client
+        .post(&format!(
+            "https://content.dropboxapi.com/2/files/upload_session/{}",
+            endpoint
+        ))
+        .header(
+            header::AUTHORIZATION,
+            &format!("Bearer {}", &access_token.value),
+        )
+        .header(header::CONTENT_TYPE, "application/octet-stream")
+        .header("Dropbox-API-Arg", &serde_json::to_string(arg)?)
+        .body(chunk.to_vec())
+        .send()
+        .await?;
+
+    Ok(res)
+}
+
+async fn upload_session_start(
+    client: &Client,
+    access_token: &AccessToken,
+    chunk: &[u8],
+) -> Result<String> {
+    let mut arg = HashMap::new();
+    arg.insert("close", false);
+
+    let result: UploadSessionStartResult =
+        post_upload_session(client, access_token, "start", &arg, chunk)
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+    Ok(result.session_id)
+}
+
+// 前回のリクエストが届いたのに応答だけが失われた場合は、オフセットが合わないエラーになる。
+// その場合はこのチャンクまで受け取られているかを確認する
+fn is_already_appended(error: &str, offset: u64, len: usize) -> bool {
+    match serde_json::from_str(error) {
+        Ok(UploadSessionError {
+            error: UploadSessionLookupError::IncorrectOffset { correct_offset },
+        }) => correct_offset == offset + len as u64,
+        _ => false,
+    }
+}
+
+async fn upload_session_append(
+    client: &Client,
+    access_token: &AccessToken,
+    session_id: &str,
+    offset: u64,
+    chunk: &[u8],
+) -> Result<()> {
+    #[derive(Serialize)]
+    struct Arg<'a> {
+        cursor: UploadSessionCursor<'a>,
+        close: bool,
+    }
+
+    let arg = Arg {
+        cursor: UploadSessionCursor { session_id, offset },
+        close: false,
+    };
+    let res = post_upload_session(client, access_token, "append_v2", &arg, chunk).await?;
+
+    if res.status() == StatusCode::CONFLICT {
+        let error = res.text().await?;
+        if is_already_appended(&error, offset, chunk.len()) {
+            return Ok(());
+        }
+        return Err(anyhow!("アップロードに失敗しました: {}", error));
+    }
+    res.error_for_status()?;
+
+    Ok(())
+}
+
+async fn upload_session_finish(
+    client: &Client,
+    access_token: &AccessToken,
+    session_id: &str,
+    offset: u64,
+    path: &str,
+    chunk: &[u8],
+) -> Result<FileInfo> {
+    #[derive(Serialize)]
+    struct Arg<'a> {
+        cursor: UploadSessionCursor<'a>,
+        commit: CommitInfo<'a>,
+    }
+
+    let arg = Arg {
+        cursor: UploadSessionCursor { session_id, offset },
+        commit: CommitInfo {
+            path,
+            mode: "overwrite",
+        },
+    };
+
+    let info = post_upload_session(client, access_token, "finish", &arg, chunk)
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    Ok(info)
+}
+
+// 分割してアップロードする。チャンクごとに失敗したら繰り返す
+async fn upload_file_in_chunks(
+    client: &Client,
+    access_token: &AccessToken,
+    path: &str,
+    contents: &[u8],
+) -> Result<FileInfo> {
+    let mut chunks = contents.chunks(UPLOAD_CHUNK_SIZE);
+
+    // 最初のチャンクでセッションを開始して、最後のチャンクで完了する
+    let first = chunks.next().unwrap_or_default();
+    let last = chunks.next_back().unwrap_or_default();
+
+    let session_id = retry(|| upload_session_start(client, access_token, first))
+        .await
+        .with_context(|| format!("{}のアップロードを開始できませんでした", path))?;
+    let mut offset = first.len() as u64;
+
+    for chunk in chunks {
+        retry(|| upload_session_append(client, access_token, &session_id, offset, chunk))
+            .await
+            .with_context(|| format!("{}のアップロードに失敗しました", path))?;
+        offset += chunk.len() as u64;
+    }
+
+    let finished =
+        retry(|| upload_session_finish(client, access_token, &session_id, offset, path, last))
+            .await;
+    match finished {
+        Ok(info) => Ok(info),
+        Err(err) => {
+            // 完了したのに応答だけが失われた場合は、繰り返すとセッションが閉じているため失敗する。
+            // 書き込まれたファイルの内容が一致すれば成功とみなす
+            match get_metadata(client, access_token, path).await {
+                Ok(Some(info)) if info.content_hash.as_deref() == Some(&content_hash(contents)) => {
+                    Ok(info)
+                }
+                _ => Err(err)
+                    .with_context(|| format!("{}のアップロードを完了できませんでした", path)),
+            }
+        }
+    }
+}
+
+// ファイルの情報を取得する。存在しなければNoneを返す
+pub async fn get_metadata(
+    client: &Client,
+    access_token: &AccessToken,
+    path: &str,
+) -> Result<Option<FileInfo>> {
+    let mut parameters = HashMap::new();
+    parameters.insert("path", path);
+
+    let res = client
+        .post("https://api.dropboxapi.com/2/files/get_metadata")
+        .header(
+            header::AUTHORIZATION,
+            &format!("Bearer {}", &access_token.value),
+        )
+        .json(&parameters)
+        .send()
+        .await?;
+
+    // 存在しないパスは409 (path/not_found) になる
+    if res.status() == StatusCode::CONFLICT {
+        let error = res.text().await?;
+        return match serde_json::from_str::<ErrorResponse>(&error) {
+            Ok(res) if res.error_summary.starts_with("path/not_found") => Ok(None),
+            _ => Err(anyhow!("{}の情報を取得できませんでした: {}", path, error)),
+        };
+    }
+
+    Ok(Some(res.error_for_status()?.json().await?))
+}
+
+pub async fn delete_file(client: &Client, access_token: &AccessToken, path: &str) -> Result<()> {
+    let mut parameters = HashMap::new();
+    parameters.insert("path", path);
+
+    client
+        .post("https://api.dropboxapi.com/2/files/delete_v2")
+        .header(
+            header::AUTHORIZATION,
+            &format!("Bearer {}", &access_token.value),
+        )
+        .json(&parameters)
+        .send()
+        .await?
+        .error_for_status()?;
+
+    Ok(())
+}
+
+// フォルダの一覧に含まれる項目。変更を取得した場合は削除されたファイルも含まれる
+#[derive(Debug, Deserialize)]
+#[serde(tag = ".tag", rename_all = "lowercase")]
+pub enum Metadata {
+    File(FileInfo),
+    Folder,
+    Deleted { name: String },
+}
+
 #[derive(Debug, Deserialize)]
 pub struct FileList {
-    entries: Vec<FileInfo>,
+    entries: Vec<Metadata>,
+    cursor: String,
+    has_more: bool,
 }
 
-pub async fn list_files(
+#[derive(Debug, Deserialize)]
+struct ErrorResponse {
+    error_summary: String,
+}
+
+// フォルダが存在しなければNoneを返す
+async fn list_folder(
     client: &Client,
     access_token: &AccessToken,
     path: &str,
-) -> Result<Vec<FileInfo>> {
+) -> Result<Option<FileList>> {
     let mut parameters = HashMap::new();
     parameters.insert("path", path);
 
-    let list: FileList = client
+    let res = client
         .post("https://api.dropboxapi.com/2/files/list_folder")
         .header(
             header::AUTHORIZATION,
@@ -187,19 +597,31 @@ pub async fn list_files(
         )
         .json(&parameters)
         .send()
-        .await?
-        .json()
         .await?;
 
-    Ok(list.entries)
+    // 存在しないフォルダは409 (path/not_found) になる
+    if res.status() == StatusCode::CONFLICT {
+        let error = res.text().await?;
+        return match serde_json::from_str::<ErrorResponse>(&error) {
+            Ok(res) if res.error_summary.starts_with("path/not_found") => Ok(None),
+            _ => Err(anyhow!("{}の一覧を取得できませんでした: {}", path, error)),
+        };
+    }
+
+    Ok(Some(res.error_for_status()?.json().await?))
 }
 
-pub async fn create_folder(client: &Client, access_token: &AccessToken, path: &str) -> Result<()> {
+// 続きを取得する。カーソルが無効になっていればNoneを返す
+async fn list_folder_continue(
+    client: &Client,
+    access_token: &AccessToken,
+    cursor: &str,
+) -> Result<Option<FileList>> {
     let mut parameters = HashMap::new();
-    parameters.insert("path", path);
+    parameters.insert("cursor", cursor);
 
     let res = client
-        .post("https://api.dropboxapi.com/2/files/create_folder_v2")
+        .post("https://api.dropboxapi.com/2/files/list_folder/continue")
         .header(
             header::AUTHORIZATION,
             &format!("Bearer {}", &access_token.value),
@@ -208,10 +630,134 @@ pub async fn create_folder(client: &Client, access_token: &AccessToken, path: &s
         .send()
         .await?;
 
-    let j: reqwest::Result<serde_json::Value> = res.json().await;
-    if j.is_err() {
-        eprintln!("フォルダの作成に失敗しました");
+    // カーソルが無効になると409 (reset) になる
+    if res.status() == StatusCode::CONFLICT {
+        return Ok(None);
     }
 
-    Ok(())
+    Ok(Some(res.error_for_status()?.json().await?))
+}
+
+// フォルダ内のファイルをすべて取得する。変更の取得に使うカーソルも返す。
+// フォルダが存在しなければNoneを返す
+pub async fn list_files(
+    client: &Client,
+    access_token: &AccessToken,
+    path: &str,
+) -> Result<Option<(Vec<FileInfo>, String)>> {
+    let mut files = Vec::new();
+
+    let mut list = match list_folder(client, access_token, path).await? {
+        Some(list) => list,
+        None => return Ok(None),
+    };
+    loop {
+        files.extend(list.entries.into_iter().filter_map(|entry| match entry {
+            Metadata::File(info) => Some(info),
+            _ => None,
+        }));
+
+        if !list.has_more {
+            return Ok(Some((files, list.cursor)));
+        }
+
+        list = list_folder_continue(client, access_token, &list.cursor)
+            .await?
+            .ok_or_else(|| anyhow!("{}の一覧の取得中にカーソルが無効になりました", path))?;
+    }
+}
+
+// カーソルの時点からの変更をすべて取得する。カーソルが無効になっていればNoneを返す
+pub async fn list_changes(
+    client: &Client,
+    access_token: &AccessToken,
+    cursor: &str,
+) -> Result<Option<(Vec<Metadata>, String)>> {
+    let mut entries = Vec::new();
+    let mut cursor = cursor.to_string();
+
+    loop {
+        let list = match list_folder_continue(client, access_token, &cursor).await? {
+            Some(list) => list,
+            None => return Ok(None),
+        };
+
+        entries.extend(list.entries);
+        cursor = list.cursor;
+
+        if !list.has_more {
+            return Ok(Some((entries, cursor)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_content_hash() {
+        let empty = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+        assert_eq!(empty, content_hash(b""));
+
+        // 4MBを超える場合はブロックごとのハッシュから計算する
+        let contents = vec![1u8; CONTENT_HASH_BLOCK_SIZE + 1];
+        let mut hashes = Sha256::digest(&contents[..CONTENT_HASH_BLOCK_SIZE]).to_vec();
+        hashes.extend(Sha256::digest(&contents[CONTENT_HASH_BLOCK_SIZE..]));
+        let expected: String = Sha256::digest(&hashes)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        assert_eq!(expected, content_hash(&contents));
+    }
+
+    #[test]
+    fn test_is_already_appended() {
+        let error = r#"{
+            "error_summary": "incorrect_offset/..",
+            "error": {".tag": "incorrect_offset", "correct_offset": 16}
+        }"#;
+        assert!(is_already_appended(error, 8, 8));
+        assert!(!is_already_appended(error, 16, 8));
+
+        let error = r#"{"error_summary": "not_found/", "error": {".tag": "not_found"}}"#;
+        assert!(!is_already_appended(error, 8, 8));
+    }
+
+    #[test]
+    fn test_parse_file_list() {
+        let json = r#"{
+            "entries": [
+                {
+                    ".tag": "file",
+                    "name": "2020-03-29-2020-04-04.json",
+                    "id": "id:a4ayc_80_OEAAAAAAAAAXw",
+                    "client_modified": "2020-04-05T10:00:00Z",
+                    "server_modified": "2020-04-05T10:00:00Z",
+                    "rev": "a1c10ce0dd78",
+                    "size": 7212,
+                    "content_hash": "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+                },
+                {".tag": "folder", "name": "old", "id": "id:a4ayc_80_OEAAAAAAAAAXz"},
+                {".tag": "deleted", "name": "2020-04-05-2020-04-11.json"}
+            ],
+            "cursor": "ZtkX9_EHj3x7PMkVuFIhwKYXEpwpLwyxp9vMKomUhllil9q7eWiAu",
+            "has_more": true
+        }"#;
+
+        let list: FileList = serde_json::from_str(json).unwrap();
+        assert!(list.has_more);
+        assert_eq!(3, list.entries.len());
+        match &list.entries[0] {
+            Metadata::File(info) => {
+                assert_eq!("a1c10ce0dd78", info.rev);
+                assert!(info.content_hash.is_some());
+            }
+            entry => panic!("{:?}", entry),
+        }
+        match &list.entries[2] {
+            Metadata::Deleted { name } => assert_eq!("2020-04-05-2020-04-11.json", name),
+            entry => panic!("{:?}", entry),
+        }
+    }
 }
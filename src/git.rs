@@ -0,0 +1,241 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{anyhow, Context as _, Result};
+
+use crate::config::GitConfig;
+
+// pagesとimagesだけを管理する
+const GITIGNORE: &str = "/*
+!/.gitignore
+!/.gitattributes
+!/pages/
+!/images/
+*.tmp
+";
+
+// 週ごとのファイルはmerge-driverで統合する
+const GITATTRIBUTES: &str = "/pages/*.json merge=diary2
+/images/** binary
+";
+
+const REMOTE: &str = "origin";
+
+fn git(dir: &Path, args: &[&str]) -> Result<String> {
+    let output = Command::new("git")
+        .current_dir(dir)
+        .args(args)
+        .output()
+        .context("gitを実行できませんでした")?;
+
+    if !output.status.success() {
+        return Err(anyhow!(
+            "git {} に失敗しました: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+// 終了コードだけを確認する
+fn git_succeeds(dir: &Path, args: &[&str]) -> Result<bool> {
+    let output = Command::new("git")
+        .current_dir(dir)
+        .args(args)
+        .output()
+        .context("gitを実行できませんでした")?;
+
+    Ok(output.status.success())
+}
+
+// ディレクトリをリポジトリにする。既にリポジトリであれば設定だけを更新する
+pub fn init(dir: &Path) -> Result<()> {
+    if !dir.join(".git").exists() {
+        git(dir, &["init", "-q"])?;
+    }
+
+    for (file_name, contents) in &[(".gitignore", GITIGNORE), (".gitattributes", GITATTRIBUTES)] {
+        let path = dir.join(file_name);
+        if !path.exists() {
+            fs::write(&path, contents)?;
+        }
+    }
+
+    // 実行ファイルの場所が変わっても動くように毎回設定する
+    let exe = env::current_exe()?;
+    git(
+        dir,
+        &["config", "merge.diary2.name", "diary2 week page merge"],
+    )?;
+    git(
+        dir,
+        &[
+            "config",
+            "merge.diary2.driver",
+            &format!("\"{}\" merge-driver %O %A %B", exe.display()),
+        ],
+    )?;
+
+    // コミットできるようにする
+    if !git_succeeds(dir, &["config", "user.name"])? {
+        git(dir, &["config", "user.name", "diary2"])?;
+    }
+    if !git_succeeds(dir, &["config", "user.email"])? {
+        git(dir, &["config", "user.email", "diary2@localhost"])?;
+    }
+
+    Ok(())
+}
+
+// 変更をすべてコミットする。変更がなければコミットせずにfalseを返す
+pub fn commit(dir: &Path, message: &str) -> Result<bool> {
+    init(dir)?;
+
+    git(dir, &["add", "-A"])?;
+    if git_succeeds(dir, &["diff", "--cached", "--quiet"])? {
+        return Ok(false);
+    }
+
+    git(dir, &["commit", "-q", "-m", message])?;
+
+    Ok(true)
+}
+
+fn set_remote(dir: &Path, url: &str) -> Result<()> {
+    let remotes = git(dir, &["remote"])?;
+    if remotes.lines().any(|remote| remote == REMOTE) {
+        git(dir, &["remote", "set-url", REMOTE, url])?;
+    } else {
+        git(dir, &["remote", "add", REMOTE, url])?;
+    }
+
+    Ok(())
+}
+
+fn head(dir: &Path) -> Result<Option<String>> {
+    if git_succeeds(dir, &["rev-parse", "--verify", "-q", "HEAD"])? {
+        Ok(Some(git(dir, &["rev-parse", "HEAD"])?.trim().to_string()))
+    } else {
+        Ok(None)
+    }
+}
+
+// ローカルの変更をコミットしてから、リモートの変更をマージしてプッシュする
+pub fn sync(dir: &Path, config: &GitConfig) -> Result<()> {
+    commit(dir, "同期前の変更")?;
+    set_remote(dir, &config.remote)?;
+
+    git(dir, &["fetch", "-q", REMOTE])?;
+
+    let remote_branch = format!("{}/{}", REMOTE, config.branch);
+    let old_head = head(dir)?;
+
+    // 初回の同期ではリモートにまだブランチがない
+    if git_succeeds(
+        dir,
+        &[
+            "rev-parse",
+            "--verify",
+            "-q",
+            &format!("refs/remotes/{}", remote_branch),
+        ],
+    )? {
+        println!("{}をマージしています...", remote_branch);
+
+        // 画像が衝突した場合はローカルの画像を優先する
+        let merged = git(
+            dir,
+            &[
+                "merge",
+                "-q",
+                "--no-edit",
+                "--allow-unrelated-histories",
+                "-X",
+                "ours",
+                &remote_branch,
+            ],
+        );
+        if let Err(err) = merged {
+            let _ = git(dir, &["merge", "--abort"]);
+            return Err(err).context("マージに失敗しました");
+        }
+    }
+
+    if head(dir)?.is_none() {
+        return Ok(());
+    }
+
+    println!("{}にプッシュしています...", config.remote);
+
+    let pushed = git(
+        dir,
+        &[
+            "push",
+            "-q",
+            REMOTE,
+            &format!("HEAD:refs/heads/{}", config.branch),
+        ],
+    );
+    if let Err(err) = pushed {
+        // マージする前に戻して、ページのバックアップと食い違わないようにする
+        if let Some(old_head) = old_head {
+            git(dir, &["reset", "-q", "--hard", &old_head])?;
+        }
+        return Err(err).context("プッシュに失敗しました");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn test_sync() {
+        let root = TempDir::new();
+        let remote = root.path().join("remote.git");
+        let dir1 = root.path().join("dir1");
+        let dir2 = root.path().join("dir2");
+        for dir in &[&remote, &dir1, &dir2] {
+            fs::create_dir_all(dir).unwrap();
+        }
+        git(&remote, &["init", "-q", "--bare"]).unwrap();
+
+        let config = GitConfig {
+            remote: remote.to_string_lossy().to_string(),
+            branch: String::from("master"),
+        };
+
+        // それぞれの端末で別の週に書く
+        fs::create_dir_all(dir1.join("pages")).unwrap();
+        fs::write(dir1.join("pages").join("week1.json"), "1").unwrap();
+        fs::write(dir1.join("edited_entries.json"), "{}").unwrap();
+        assert!(commit(&dir1, "week1").unwrap());
+        assert!(!commit(&dir1, "week1").unwrap());
+        sync(&dir1, &config).unwrap();
+
+        fs::create_dir_all(dir2.join("pages")).unwrap();
+        fs::write(dir2.join("pages").join("week2.json"), "2").unwrap();
+        sync(&dir2, &config).unwrap();
+        assert_eq!(
+            "1",
+            fs::read_to_string(dir2.join("pages").join("week1.json")).unwrap()
+        );
+
+        sync(&dir1, &config).unwrap();
+        assert_eq!(
+            "2",
+            fs::read_to_string(dir1.join("pages").join("week2.json")).unwrap()
+        );
+
+        // pagesとimages以外は管理しない
+        let files = git(&dir1, &["ls-files"]).unwrap();
+        assert!(!files.contains("edited_entries.json"));
+    }
+}
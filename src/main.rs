@@ -1,26 +1,32 @@
 #[macro_use]
 extern crate serde_derive;
 
+mod backend;
 mod commands;
 mod config;
+mod crypto;
 mod dropbox;
+mod git;
+mod migration;
 mod page;
+mod peer;
 mod secret;
 mod storage;
+#[cfg(test)]
+mod testing;
 
 use std::env;
 use std::path::{Path, PathBuf};
 use std::process;
 
 use anyhow::{Context, Result};
-use clap::{App, Arg, SubCommand};
+use clap::{App, AppSettings, Arg, SubCommand};
 use tokio::fs;
 use tokio::io;
 
 use config::Config;
 
 const CONFIG_FILE: &str = "config.toml";
-const PAGE_VERSION_FILE: &str = "page_version";
 
 async fn load_config(config_file_path: &Path) -> Result<Config> {
     if !config_file_path.exists() {
@@ -47,7 +53,7 @@ fn get_directory() -> PathBuf {
 }
 
 async fn get_page_version(base: &Path) -> u32 {
-    let file_path = base.join(PAGE_VERSION_FILE);
+    let file_path = base.join(migration::PAGE_VERSION_FILE);
 
     if file_path.exists() {
         let version = fs::read_to_string(file_path)
@@ -98,21 +104,47 @@ async fn main() -> Result<()> {
             ),
         )
         .subcommand(
-            SubCommand::with_name("list").alias("ls").arg(
-                Arg::with_name("limit")
-                    .takes_value(true)
-                    .long("limit")
-                    .short("l"),
-            ),
+            SubCommand::with_name("list")
+                .alias("ls")
+                .arg(
+                    Arg::with_name("limit")
+                        .takes_value(true)
+                        .long("limit")
+                        .short("l"),
+                )
+                .arg(Arg::with_name("id").long("id").short("i"))
+                .arg(
+                    Arg::with_name("tag")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .long("tag")
+                        .short("g"),
+                )
+                .arg(Arg::with_name("reveal").long("reveal").short("r")),
         )
         .subcommand(
-            SubCommand::with_name("new").arg(Arg::with_name("hidden").long("hidden").short("d")),
+            SubCommand::with_name("new")
+                .arg(Arg::with_name("hidden").long("hidden").short("d"))
+                .arg(
+                    Arg::with_name("date")
+                        .takes_value(true)
+                        .long("date")
+                        .short("D"),
+                )
+                .arg(
+                    Arg::with_name("time")
+                        .takes_value(true)
+                        .long("time")
+                        .short("t"),
+                ),
         )
         .subcommand(SubCommand::with_name("lastdt"))
         .subcommand(
             SubCommand::with_name("show")
                 .arg(Arg::with_name("date").index(1))
-                .arg(Arg::with_name("stdout").long("stdout").short("s")),
+                .arg(Arg::with_name("stdout").long("stdout").short("s"))
+                .arg(Arg::with_name("reveal").long("reveal").short("r")),
         )
         .subcommand(
             SubCommand::with_name("search")
@@ -121,17 +153,103 @@ async fn main() -> Result<()> {
                 .arg(Arg::with_name("text").long("text").short("b"))
                 .arg(Arg::with_name("show-first").long("show-first").short("f"))
                 .arg(Arg::with_name("stdout").long("stdout").short("s"))
+                .arg(Arg::with_name("id").long("id").short("i"))
+                .arg(
+                    Arg::with_name("tag")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .long("tag")
+                        .short("g"),
+                )
                 .arg(
                     Arg::with_name("limit")
                         .takes_value(true)
                         .long("limit")
                         .short("l"),
-                ),
+                )
+                .arg(Arg::with_name("reveal").long("reveal").short("r")),
         )
         .subcommand(SubCommand::with_name("amend"))
+        .subcommand(
+            SubCommand::with_name("edit").arg(Arg::with_name("target").index(1).required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("delete")
+                .alias("rm")
+                .arg(Arg::with_name("id").index(1).required(true)),
+        )
+        .subcommand(SubCommand::with_name("hide").arg(Arg::with_name("id").index(1)))
+        .subcommand(
+            SubCommand::with_name("unhide").arg(Arg::with_name("id").index(1).required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("history").arg(Arg::with_name("id").index(1).required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("diff")
+                .arg(Arg::with_name("id").index(1).required(true))
+                .arg(Arg::with_name("rev1").index(2))
+                .arg(Arg::with_name("rev2").index(3))
+                .arg(Arg::with_name("char").long("char").short("c")),
+        )
+        .subcommand(
+            SubCommand::with_name("revert")
+                .arg(Arg::with_name("id").index(1).required(true))
+                .arg(Arg::with_name("rev").index(2).required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("retime")
+                .arg(Arg::with_name("id").index(1).required(true))
+                .arg(Arg::with_name("datetime").index(2).required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("trash")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(SubCommand::with_name("list").alias("ls"))
+                .subcommand(
+                    SubCommand::with_name("restore")
+                        .arg(Arg::with_name("id").index(1).required(true)),
+                )
+                .subcommand(
+                    SubCommand::with_name("empty")
+                        .arg(Arg::with_name("all").long("all").short("a")),
+                ),
+        )
+        .subcommand(SubCommand::with_name("tags"))
         .subcommand(SubCommand::with_name("auth"))
-        .subcommand(SubCommand::with_name("sync"))
-        .subcommand(SubCommand::with_name("fixpage"))
+        .subcommand(
+            SubCommand::with_name("sync")
+                .arg(
+                    Arg::with_name("peer")
+                        .takes_value(true)
+                        .long("peer")
+                        .short("p"),
+                )
+                .arg(Arg::with_name("dry-run").long("dry-run").short("n")),
+        )
+        .subcommand(
+            SubCommand::with_name("serve-sync").arg(
+                Arg::with_name("bind")
+                    .takes_value(true)
+                    .long("bind")
+                    .short("b"),
+            ),
+        )
+        .subcommand(SubCommand::with_name("encrypt"))
+        .subcommand(SubCommand::with_name("decrypt"))
+        .subcommand(
+            SubCommand::with_name("merge-driver")
+                .about("merge week files (used by git)")
+                .setting(AppSettings::Hidden)
+                .arg(Arg::with_name("base").index(1).required(true))
+                .arg(Arg::with_name("ours").index(2).required(true))
+                .arg(Arg::with_name("theirs").index(3).required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("fixpage")
+                .arg(Arg::with_name("dry-run").long("dry-run").short("n")),
+        )
         .get_matches();
 
     // 設定ファイルを読み込む
@@ -167,6 +285,10 @@ async fn main() -> Result<()> {
         process::exit(2);
     }
 
+    if let Err(err) = commands::remove_plaintext_files(&directory) {
+        eprintln!("一時ファイルの削除に失敗しました: {}", err);
+    }
+
     let ctx = commands::Context::new(
         &directory,
         &config_file_path,
@@ -183,9 +305,23 @@ async fn main() -> Result<()> {
         "lastdt" => commands::lastdt(ctx).await,
         "show" => commands::show(ctx).await,
         "amend" => commands::amend(ctx).await,
+        "edit" => commands::edit(ctx).await,
+        "delete" => commands::delete(ctx).await,
+        "hide" => commands::hide(ctx).await,
+        "unhide" => commands::unhide(ctx).await,
+        "history" => commands::history(ctx).await,
+        "diff" => commands::diff(ctx).await,
+        "revert" => commands::revert(ctx).await,
+        "retime" => commands::retime(ctx).await,
+        "trash" => commands::trash(ctx).await,
         "search" => commands::search(ctx).await,
+        "tags" => commands::tags(ctx).await,
         "auth" => commands::auth(ctx).await,
         "sync" => commands::sync(ctx).await,
+        "serve-sync" => commands::serve_sync(ctx).await,
+        "encrypt" => commands::encrypt(ctx).await,
+        "decrypt" => commands::decrypt(ctx).await,
+        "merge-driver" => commands::merge_driver(ctx).await,
         "fixpage" => commands::fixpage(ctx).await,
         _ => panic!(),
     };
@@ -199,12 +335,5 @@ async fn main() -> Result<()> {
         std::process::exit(1);
     }
 
-    if name == "fixpage" {
-        let file_path = directory.join(PAGE_VERSION_FILE);
-        fs::write(&file_path, &format!("{}", page::CURRENT_PAGE_VERSION))
-            .await
-            .unwrap();
-    }
-
     Ok(())
 }
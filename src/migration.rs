@@ -0,0 +1,364 @@
+use std::error;
+use std::fmt;
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use serde_json::{Map, Value};
+use uuid::Uuid;
+
+use crate::page::{self, WeekPage, CURRENT_PAGE_VERSION};
+use crate::storage::{self, Area, Storage, TrashedPage};
+
+pub const PAGE_VERSION_FILE: &str = "page_version";
+
+// 週ごとのファイルにバージョンを記録するようになったバージョン
+const VERSIONED_SINCE: u32 = 4;
+
+type Object = Map<String, Value>;
+
+// このdiary2より新しい形式で保存されている
+#[derive(Debug)]
+pub struct NewerVersionError {
+    pub version: u32,
+}
+
+impl fmt::Display for NewerVersionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "バージョン{}の形式で保存されています。このdiary2はバージョン{}までしか対応していないため、diary2を更新してください",
+            self.version, CURRENT_PAGE_VERSION
+        )
+    }
+}
+
+impl error::Error for NewerVersionError {}
+
+// 1つ前のバージョンからの変換手順
+struct Migration {
+    // 変換前のバージョン。変換後はfrom + 1になる
+    from: u32,
+    description: &'static str,
+    // 週ごとのファイル全体の変換
+    week_page: fn(&mut Object) -> Result<()>,
+    // 各ページの変換
+    page: fn(&mut Object) -> Result<()>,
+}
+
+// 保存形式を変更するときはここに変換手順を追加する
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        from: 1,
+        description: "ページにIDを追加",
+        week_page: nop,
+        page: add_id,
+    },
+    Migration {
+        from: 2,
+        description: "ページにタグを追加",
+        week_page: nop,
+        page: add_tags,
+    },
+    Migration {
+        from: 3,
+        description: "週ごとのファイルにバージョンを追加",
+        week_page: add_version,
+        page: nop,
+    },
+    Migration {
+        from: 4,
+        description: "ページに編集履歴を追加",
+        week_page: nop,
+        page: add_revisions,
+    },
+    Migration {
+        from: 5,
+        description: "隠しページを暗号化できるようにする",
+        week_page: nop,
+        page: add_sealed,
+    },
+];
+
+fn nop(_: &mut Object) -> Result<()> {
+    Ok(())
+}
+
+fn add_id(page: &mut Object) -> Result<()> {
+    page.insert(String::from("id"), Value::from(Uuid::new_v4().to_string()));
+    Ok(())
+}
+
+fn add_tags(page: &mut Object) -> Result<()> {
+    // タイトルと本文中の "#タグ" をタグとして取り込む
+    let mut tags = Vec::new();
+    for key in &["title", "text"] {
+        let s = page
+            .get(*key)
+            .and_then(Value::as_str)
+            .ok_or_else(|| anyhow!("ページに `{}` がありません", key))?;
+
+        for tag in page::extract_tags(s) {
+            if !tags.contains(&tag) {
+                tags.push(tag);
+            }
+        }
+    }
+
+    page.insert(String::from("tags"), Value::from(tags));
+    Ok(())
+}
+
+fn add_version(wpage: &mut Object) -> Result<()> {
+    wpage.insert(String::from("version"), Value::from(VERSIONED_SINCE));
+    Ok(())
+}
+
+fn add_revisions(page: &mut Object) -> Result<()> {
+    page.insert(String::from("revisions"), Value::Array(Vec::new()));
+    Ok(())
+}
+
+fn add_sealed(page: &mut Object) -> Result<()> {
+    page.insert(String::from("sealed"), Value::Null);
+    Ok(())
+}
+
+fn as_object(value: &mut Value) -> Result<&mut Object> {
+    value
+        .as_object_mut()
+        .ok_or_else(|| anyhow!("JSONがオブジェクトではありません"))
+}
+
+// fromから現在のバージョンまでの変換手順を取得する
+fn steps(from: u32) -> Result<Vec<&'static Migration>> {
+    if from > CURRENT_PAGE_VERSION {
+        return Err(NewerVersionError { version: from }.into());
+    }
+
+    (from..CURRENT_PAGE_VERSION)
+        .map(|version| {
+            MIGRATIONS
+                .iter()
+                .find(|migration| migration.from == version)
+                .ok_or_else(|| anyhow!("バージョン{}から変換する方法がありません", version))
+        })
+        .collect()
+}
+
+// 変換手順の説明を取得する
+pub fn descriptions(from: u32) -> Result<Vec<(u32, &'static str)>> {
+    Ok(steps(from)?
+        .into_iter()
+        .map(|migration| (migration.from, migration.description))
+        .collect())
+}
+
+// 週ごとのファイルに記録されたバージョンを取得する。
+// 記録されていない古いファイルは内容から推測する
+fn detect_version(value: &Value) -> Result<Option<u32>> {
+    if let Some(version) = value.get("version") {
+        return version
+            .as_u64()
+            .map(|version| Some(version as u32))
+            .ok_or_else(|| anyhow!("バージョンが数字ではありません"));
+    }
+
+    let first_page = value
+        .get("pages")
+        .and_then(Value::as_array)
+        .and_then(|pages| pages.first());
+
+    Ok(match first_page {
+        Some(page) if page.get("id").is_none() => Some(1),
+        Some(page) if page.get("tags").is_none() => Some(2),
+        Some(_) => Some(3),
+        // ページがなければ推測できない
+        None => None,
+    })
+}
+
+fn migrate_week_page_value(mut value: Value, from: u32) -> Result<WeekPage> {
+    for migration in steps(from)? {
+        let wpage = as_object(&mut value)?;
+        (migration.week_page)(wpage)?;
+
+        // バージョンが記録されていれば更新する
+        if wpage.contains_key("version") {
+            wpage.insert(String::from("version"), Value::from(migration.from + 1));
+        }
+
+        let pages = wpage
+            .get_mut("pages")
+            .and_then(Value::as_array_mut)
+            .ok_or_else(|| anyhow!("`pages` がありません"))?;
+        for page in pages {
+            (migration.page)(as_object(page)?)?;
+        }
+    }
+
+    // 現在の形式として読み込めるか検証する
+    let wpage = serde_json::from_value(value)?;
+    Ok(wpage)
+}
+
+// 週ごとのファイルを現在の形式に変換する。
+// ファイルにバージョンが記録されていなければfromのバージョンとして扱う
+pub fn migrate_week_page(json: &str, from: u32) -> Result<String> {
+    let value: Value = serde_json::from_str(json)?;
+    let version = match value.get("version") {
+        Some(_) => detect_version(&value)?.unwrap(),
+        None => from,
+    };
+
+    let wpage = migrate_week_page_value(value, version)?;
+    Ok(serde_json::to_string(&wpage)?)
+}
+
+// 週ごとのファイルを読み込む。古い形式の場合は現在の形式に変換する
+pub fn parse_week_page(json: &str) -> Result<WeekPage> {
+    let value: Value = serde_json::from_str(json)?;
+    let version = detect_version(&value)?.unwrap_or(VERSIONED_SINCE - 1);
+
+    if version == CURRENT_PAGE_VERSION {
+        Ok(serde_json::from_value(value)?)
+    } else {
+        migrate_week_page_value(value, version)
+    }
+}
+
+// ゴミ箱のページをfromから現在の形式に変換する
+pub fn migrate_trashed_page(json: &str, from: u32) -> Result<String> {
+    let mut value: Value = serde_json::from_str(json)?;
+
+    for migration in steps(from)? {
+        let page = as_object(&mut value)?
+            .get_mut("page")
+            .ok_or_else(|| anyhow!("`page` がありません"))?;
+        (migration.page)(as_object(page)?)?;
+    }
+
+    // 現在の形式として読み込めるか検証する
+    let trashed_page: TrashedPage = serde_json::from_value(value)?;
+    Ok(serde_json::to_string(&trashed_page)?)
+}
+
+// すべてのファイルを変換して検証する。書き込みはしない
+pub async fn prepare(storage: &dyn Storage, from: u32) -> Result<Vec<(Area, String, String)>> {
+    let mut converted = Vec::new();
+    let mut errors = Vec::new();
+
+    let week_file_names = storage::week_file_names(storage).await?;
+    let trash_file_names = storage::trash_file_names(storage).await?;
+
+    let files = week_file_names
+        .into_iter()
+        .map(|file_name| {
+            (
+                Area::Pages,
+                file_name,
+                migrate_week_page as fn(&str, u32) -> Result<String>,
+            )
+        })
+        .chain(trash_file_names.into_iter().map(|file_name| {
+            (
+                Area::TrashPages,
+                file_name,
+                migrate_trashed_page as fn(&str, u32) -> Result<String>,
+            )
+        }));
+
+    for (area, file_name, migrate) in files {
+        let json = storage::read_to_string(storage, area, &file_name).await?;
+        match migrate(&json, from) {
+            Ok(json) => converted.push((area, file_name, json)),
+            Err(err) => errors.push(format!("{}: {}", file_name, err)),
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(anyhow!(
+            "変換できないファイルがあります\n{}",
+            errors.join("\n")
+        ));
+    }
+
+    Ok(converted)
+}
+
+// 変換したファイルを書き込む
+pub async fn apply(storage: &dyn Storage, converted: Vec<(Area, String, String)>) -> Result<()> {
+    for (area, file_name, json) in converted {
+        storage.write(area, &file_name, json.as_bytes()).await?;
+    }
+
+    Ok(())
+}
+
+pub async fn write_page_version(directory: &Path, version: u32) -> Result<()> {
+    let file_path = directory.join(PAGE_VERSION_FILE);
+    storage::write_atomically(&file_path, format!("{}", version).as_bytes()).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WEEK_PAGE_V1: &str = r##"{"pages":[{"title":"タイトル #日記","text":"本文 #旅行","hidden":false,"created_at":"2020-04-01T10:00:00Z","updated_at":["2020-04-01T10:00:00Z"]}],"uploaded_at":null}"##;
+
+    #[test]
+    fn test_migrate_week_page() {
+        let json = migrate_week_page(WEEK_PAGE_V1, 1).unwrap();
+        let wpage: WeekPage = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(1, wpage.pages.len());
+        assert!(!wpage.pages[0].id.is_empty());
+        assert_eq!(vec!["日記", "旅行"], wpage.pages[0].tags);
+    }
+
+    #[test]
+    fn test_migrate_trashed_page() {
+        let json = r##"{"page":{"id":"a","title":"タイトル","text":"本文 #旅行","hidden":false,"created_at":"2020-04-01T10:00:00Z","updated_at":[]},"deleted_at":"2020-04-02T10:00:00Z"}"##;
+        let json = migrate_trashed_page(json, 2).unwrap();
+        let trashed_page: TrashedPage = serde_json::from_str(&json).unwrap();
+
+        assert_eq!("a", trashed_page.page.id);
+        assert_eq!(vec!["旅行"], trashed_page.page.tags);
+    }
+
+    #[test]
+    fn test_migrate_invalid_week_page() {
+        // ページにタイトルがない
+        let json = r#"{"pages":[{"id":"a","text":"","hidden":false,"created_at":"2020-04-01T10:00:00Z","updated_at":[]}],"uploaded_at":null}"#;
+        assert!(migrate_week_page(json, 2).is_err());
+
+        // 変換しても現在の形式として読み込めない
+        assert!(migrate_week_page(WEEK_PAGE_V1, 2).is_err());
+
+        // 新しい形式からは変換できない
+        assert!(migrate_week_page(WEEK_PAGE_V1, CURRENT_PAGE_VERSION + 1).is_err());
+    }
+
+    #[test]
+    fn test_parse_week_page() {
+        // バージョンが記録されていない古いファイルは内容から推測して変換する
+        let wpage = parse_week_page(WEEK_PAGE_V1).unwrap();
+        assert_eq!(CURRENT_PAGE_VERSION, wpage.version);
+        assert_eq!(vec!["日記", "旅行"], wpage.pages[0].tags);
+
+        let json = r#"{"pages":[],"uploaded_at":null}"#;
+        assert_eq!(CURRENT_PAGE_VERSION, parse_week_page(json).unwrap().version);
+
+        // 新しい形式のファイルは読み込まない
+        let json = format!(
+            r#"{{"version":{},"pages":[],"uploaded_at":null}}"#,
+            CURRENT_PAGE_VERSION + 1
+        );
+        let err = parse_week_page(&json).unwrap_err();
+        assert_eq!(
+            CURRENT_PAGE_VERSION + 1,
+            err.downcast_ref::<NewerVersionError>().unwrap().version
+        );
+    }
+}
@@ -1,51 +1,180 @@
 use std::borrow::Cow;
 use std::path::PathBuf;
 
+use anyhow::Result;
 use chrono::{DateTime, Utc};
 use regex::{Captures, Regex};
 
-pub const CURRENT_PAGE_VERSION: u32 = 2;
+use crate::crypto::Keyring;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct PageV1 {
+pub const CURRENT_PAGE_VERSION: u32 = 6;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Page {
+    pub id: String,
     pub title: String,
     pub text: String,
+    pub tags: Vec<String>,
     pub hidden: bool,
     pub created_at: DateTime<Utc>,
     pub updated_at: Vec<DateTime<Utc>>,
+    // 以前の内容。古い順
+    pub revisions: Vec<Revision>,
+    // 隠しページの暗号化された内容。暗号化されている間はタイトル、本文、タグ、履歴は空になる
+    pub sealed: Option<String>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Page {
-    pub id: String,
+// 隠しページとして暗号化される内容
+#[derive(Debug, Serialize, Deserialize)]
+struct SealedContent {
+    title: String,
+    text: String,
+    tags: Vec<String>,
+    revisions: Vec<Revision>,
+}
+
+// ページの以前の内容
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Revision {
     pub title: String,
     pub text: String,
-    pub hidden: bool,
-    pub created_at: DateTime<Utc>,
-    pub updated_at: Vec<DateTime<Utc>>,
+    pub tags: Vec<String>,
+    // この内容が保存された日時
+    pub saved_at: DateTime<Utc>,
+}
+
+impl Page {
+    // 現在の内容を版として取得する
+    pub fn current_revision(&self) -> Revision {
+        Revision {
+            title: self.title.clone(),
+            text: self.text.clone(),
+            tags: self.tags.clone(),
+            saved_at: self.last_modified(),
+        }
+    }
+
+    // 1から始まる版の番号で内容を取得する。最後の番号は現在の内容
+    pub fn revision(&self, number: usize) -> Option<Revision> {
+        if number == 0 {
+            None
+        } else if number <= self.revisions.len() {
+            Some(self.revisions[number - 1].clone())
+        } else if number == self.revisions.len() + 1 {
+            Some(self.current_revision())
+        } else {
+            None
+        }
+    }
+
+    // 現在の内容の版の番号
+    pub fn current_revision_number(&self) -> usize {
+        self.revisions.len() + 1
+    }
+
+    pub fn is_sealed(&self) -> bool {
+        self.sealed.is_some()
+    }
+
+    // タイトル、本文、タグ、履歴を暗号化する
+    pub fn seal(&mut self, keyring: &mut Keyring) -> Result<()> {
+        let content = SealedContent {
+            title: std::mem::take(&mut self.title),
+            text: std::mem::take(&mut self.text),
+            tags: std::mem::take(&mut self.tags),
+            revisions: std::mem::take(&mut self.revisions),
+        };
+
+        let json = serde_json::to_string(&content)?;
+        self.sealed = Some(keyring.seal(json.as_bytes())?);
+
+        Ok(())
+    }
+
+    // 暗号化された内容を復号して戻す
+    pub fn unseal(&mut self, keyring: &mut Keyring) -> Result<()> {
+        let sealed = match &self.sealed {
+            Some(sealed) => sealed,
+            None => return Ok(()),
+        };
+
+        let json = keyring.open(sealed)?;
+        let content: SealedContent = serde_json::from_slice(&json)?;
+
+        self.title = content.title;
+        self.text = content.text;
+        self.tags = content.tags;
+        self.revisions = content.revisions;
+        self.sealed = None;
+
+        Ok(())
+    }
+
+    // 最後に更新された日時
+    pub fn last_modified(&self) -> DateTime<Utc> {
+        self.updated_at
+            .iter()
+            .max()
+            .copied()
+            .unwrap_or(self.created_at)
+    }
 }
 
+// 削除されたページの記録
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct WeekPageV1 {
-    pub pages: Vec<PageV1>,
-    pub uploaded_at: Option<DateTime<Utc>>,
+pub struct Tombstone {
+    pub id: String,
+    pub deleted_at: DateTime<Utc>,
+}
+
+// 削除後に更新されていなければ削除済みとみなす
+pub fn is_deleted(tombstones: &[Tombstone], page: &Page) -> bool {
+    tombstones
+        .iter()
+        .any(|tombstone| tombstone.id == page.id && tombstone.deleted_at >= page.last_modified())
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct WeekPage {
+    // このファイルを書き込んだときの保存形式のバージョン
+    pub version: u32,
     pub pages: Vec<Page>,
+    #[serde(default)]
+    pub deleted: Vec<Tombstone>,
     pub uploaded_at: Option<DateTime<Utc>>,
 }
 
 impl WeekPage {
     pub fn new() -> Self {
         Self {
+            version: CURRENT_PAGE_VERSION,
             pages: Vec::new(),
+            deleted: Vec::new(),
             uploaded_at: None,
         }
     }
 }
 
+// 本文中の "#タグ" を重複なしで取得する
+pub fn extract_tags(text: &str) -> Vec<String> {
+    let re = Regex::new(r"(?:^|\s)#([^\s#]+)").unwrap();
+
+    let mut tags: Vec<String> = Vec::new();
+    for cap in re.captures_iter(text) {
+        let tag = &cap[1];
+        if !tags.iter().any(|t| t == tag) {
+            tags.push(tag.to_string());
+        }
+    }
+
+    tags
+}
+
+// 画像のファイル名の先頭につける文字列
+pub fn generate_image_prefix(created_at: &DateTime<Utc>) -> String {
+    created_at.format("%Y-%m-%d_%H-%M-%S-%f_").to_string()
+}
+
 pub fn convert_image_paths_in_text<'a, F>(
     text: &'a str,
     mut f: F,
@@ -70,9 +199,96 @@ where
     (result, images)
 }
 
+// 本文で使われている画像のファイル名を取得する
+pub fn image_file_names(text: &str) -> Vec<String> {
+    let (_, images) = convert_image_paths_in_text(text, |s| s.to_string());
+    images.into_iter().map(|(_, file_name)| file_name).collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_extract_tags() {
+        let text =
+            "#旅行 京都に行った #写真\n# 見出し\nhttp://example.com/#anchor #旅行\n##見出し2";
+        assert_eq!(
+            vec![String::from("旅行"), String::from("写真")],
+            extract_tags(text)
+        );
+        assert!(extract_tags("タグなし").is_empty());
+    }
+
+    #[test]
+    fn test_revision() {
+        let old = Revision {
+            title: String::from("old"),
+            text: String::from("old text"),
+            tags: Vec::new(),
+            saved_at: Utc.ymd(2020, 4, 1).and_hms(10, 0, 0),
+        };
+
+        let page = Page {
+            id: String::from("a"),
+            title: String::from("new"),
+            text: String::from("new text"),
+            tags: Vec::new(),
+            hidden: false,
+            created_at: Utc.ymd(2020, 4, 1).and_hms(10, 0, 0),
+            updated_at: vec![
+                Utc.ymd(2020, 4, 1).and_hms(10, 0, 0),
+                Utc.ymd(2020, 4, 2).and_hms(10, 0, 0),
+            ],
+            revisions: vec![old.clone()],
+            sealed: None,
+        };
+
+        assert_eq!(2, page.current_revision_number());
+        assert_eq!(None, page.revision(0));
+        assert_eq!(Some(old), page.revision(1));
+        assert_eq!("new", page.revision(2).unwrap().title);
+        assert_eq!(
+            Utc.ymd(2020, 4, 2).and_hms(10, 0, 0),
+            page.revision(2).unwrap().saved_at
+        );
+        assert_eq!(None, page.revision(3));
+    }
+
+    #[test]
+    fn test_is_deleted() {
+        let page = Page {
+            id: String::from("a"),
+            title: String::from("title"),
+            text: String::new(),
+            tags: Vec::new(),
+            hidden: false,
+            created_at: Utc.ymd(2020, 4, 1).and_hms(10, 0, 0),
+            updated_at: vec![Utc.ymd(2020, 4, 2).and_hms(10, 0, 0)],
+            revisions: Vec::new(),
+            sealed: None,
+        };
+
+        let tombstone = |id: &str, deleted_at| Tombstone {
+            id: id.to_string(),
+            deleted_at,
+        };
+
+        assert!(is_deleted(
+            &[tombstone("a", Utc.ymd(2020, 4, 3).and_hms(0, 0, 0))],
+            &page
+        ));
+        assert!(!is_deleted(
+            &[tombstone("b", Utc.ymd(2020, 4, 3).and_hms(0, 0, 0))],
+            &page
+        ));
+        // 削除後に更新されたページは残す
+        assert!(!is_deleted(
+            &[tombstone("a", Utc.ymd(2020, 4, 1).and_hms(12, 0, 0))],
+            &page
+        ));
+    }
 
     #[test]
     fn test_convert_image_paths_in_text() {
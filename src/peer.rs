@@ -0,0 +1,566 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{anyhow, Context as _, Result};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::crypto::{self, Key};
+use crate::storage::{self, Area, Storage};
+
+pub const DEFAULT_PORT: u16 = 7878;
+pub const CODE_ENV: &str = "DIARY2_PEER_CODE";
+
+const NONCE_LEN: usize = 16;
+// 使い捨てのコード。読み間違えやすい文字は使わない
+const CODE_LEN: usize = 8;
+const CODE_CHARS: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+// 使い捨てのコードで待ち受けている場合は、この回数だけ認証に失敗したら終了する
+const MAX_FAILED_ATTEMPTS: u32 = 3;
+// 1行に含められるメッセージの長さ。画像が入るので大きめにしておく
+const MAX_MESSAGE_LEN: u64 = 64 * 1024 * 1024;
+
+const CLIENT_LABEL: &[u8] = b"client";
+const SERVER_LABEL: &[u8] = b"server";
+
+// 端末が持っている週ごとのファイルと画像
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    // ファイル名と内容のハッシュ
+    pages: HashMap<String, String>,
+    images: HashSet<String>,
+}
+
+// 1行に1つずつJSONで送る。バイナリはBase64にする
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Message {
+    // 認証
+    Challenge {
+        nonce: String,
+    },
+    Response {
+        nonce: String,
+        mac: String,
+    },
+    Accepted {
+        mac: String,
+    },
+    Rejected,
+    // 同期
+    GetManifest,
+    Manifest(Manifest),
+    Get {
+        area: Area,
+        name: String,
+    },
+    File {
+        data: String,
+    },
+    Put {
+        area: Area,
+        name: String,
+        data: String,
+    },
+    Stored,
+    Done,
+    Error {
+        message: String,
+    },
+}
+
+struct Connection {
+    stream: BufReader<TcpStream>,
+    // 認証した後はメッセージを暗号化する
+    key: Option<Key>,
+}
+
+impl Connection {
+    fn new(stream: TcpStream) -> Self {
+        Self {
+            stream: BufReader::new(stream),
+            key: None,
+        }
+    }
+
+    async fn send(&mut self, message: &Message) -> Result<()> {
+        let json = serde_json::to_vec(message)?;
+        let mut line = match &self.key {
+            Some(key) => base64::encode(&crypto::encrypt(key, &json)?).into_bytes(),
+            None => json,
+        };
+        line.push(b'\n');
+
+        self.stream
+            .get_mut()
+            .write_all(&line)
+            .await
+            .context("送信に失敗しました")?;
+
+        Ok(())
+    }
+
+    // 相手の端末でエラーが発生していればエラーを返す
+    async fn receive(&mut self) -> Result<Message> {
+        let mut line = String::new();
+        (&mut self.stream)
+            .take(MAX_MESSAGE_LEN)
+            .read_line(&mut line)
+            .await
+            .context("受信に失敗しました")?;
+        if !line.ends_with('\n') {
+            return Err(anyhow!("接続が切断されました"));
+        }
+
+        let json = match &self.key {
+            Some(key) => crypto::decrypt(key, &base64::decode(line.trim_end())?)?,
+            None => line.into_bytes(),
+        };
+
+        let message = serde_json::from_slice(&json).context("不正なメッセージを受信しました")?;
+        match message {
+            Message::Error { message } => {
+                Err(anyhow!("相手の端末でエラーが発生しました: {}", message))
+            }
+            message => Ok(message),
+        }
+    }
+}
+
+fn unexpected() -> anyhow::Error {
+    anyhow!("予期しないメッセージを受信しました")
+}
+
+// 待ち受ける側に表示するコードを生成する
+pub fn generate_code() -> String {
+    crypto::random_bytes(CODE_LEN)
+        .iter()
+        .map(|b| CODE_CHARS[*b as usize % CODE_CHARS.len()] as char)
+        .collect()
+}
+
+// 接続してきた端末が合言葉を知っているか確かめる。知らなければfalseを返す
+async fn accept(conn: &mut Connection, secret: &str) -> Result<bool> {
+    let server_nonce = crypto::random_bytes(NONCE_LEN);
+    conn.send(&Message::Challenge {
+        nonce: base64::encode(&server_nonce),
+    })
+    .await?;
+
+    let (client_nonce, mac) = match conn.receive().await? {
+        Message::Response { nonce, mac } => (base64::decode(&nonce)?, base64::decode(&mac)?),
+        _ => return Err(unexpected()),
+    };
+
+    let key = crypto::derive_peer_key(secret, &server_nonce)?;
+    if !crypto::verify_peer_mac(&key, CLIENT_LABEL, &server_nonce, &client_nonce, &mac) {
+        conn.send(&Message::Rejected).await?;
+        return Ok(false);
+    }
+
+    // 接続してきた端末もこちらを確かめられるようにする
+    let mac = crypto::peer_mac(&key, SERVER_LABEL, &server_nonce, &client_nonce);
+    conn.send(&Message::Accepted {
+        mac: base64::encode(&mac),
+    })
+    .await?;
+
+    conn.key = Some(crypto::peer_session_key(&key, &server_nonce, &client_nonce));
+
+    Ok(true)
+}
+
+// 接続先の端末と互いに合言葉を知っているか確かめる
+async fn authenticate(conn: &mut Connection, secret: &str) -> Result<()> {
+    let server_nonce = match conn.receive().await? {
+        Message::Challenge { nonce } => base64::decode(&nonce)?,
+        _ => return Err(unexpected()),
+    };
+    if server_nonce.len() != NONCE_LEN {
+        return Err(unexpected());
+    }
+
+    let client_nonce = crypto::random_bytes(NONCE_LEN);
+    let key = crypto::derive_peer_key(secret, &server_nonce)?;
+    let mac = crypto::peer_mac(&key, CLIENT_LABEL, &server_nonce, &client_nonce);
+    conn.send(&Message::Response {
+        nonce: base64::encode(&client_nonce),
+        mac: base64::encode(&mac),
+    })
+    .await?;
+
+    match conn.receive().await? {
+        Message::Accepted { mac } => {
+            let mac = base64::decode(&mac)?;
+            if !crypto::verify_peer_mac(&key, SERVER_LABEL, &server_nonce, &client_nonce, &mac) {
+                return Err(anyhow!("接続先の端末を認証できませんでした"));
+            }
+        }
+        Message::Rejected => return Err(anyhow!("コードが違います")),
+        _ => return Err(unexpected()),
+    }
+
+    conn.key = Some(crypto::peer_session_key(&key, &server_nonce, &client_nonce));
+
+    Ok(())
+}
+
+fn hash(contents: &[u8]) -> String {
+    Sha256::digest(contents)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+async fn manifest(storage: &dyn Storage) -> Result<Manifest> {
+    let mut pages = HashMap::new();
+    for file_name in storage::week_file_names(storage).await? {
+        let contents = storage.read(Area::Pages, &file_name).await?;
+        pages.insert(file_name, hash(&contents));
+    }
+
+    let images = storage.list(Area::Images).await?.into_iter().collect();
+
+    Ok(Manifest { pages, images })
+}
+
+// 相手の端末から受け取ったファイル名がディレクトリの外を指さないか確かめる
+fn check_file_name(area: Area, file_name: &str) -> Result<()> {
+    let valid = match area {
+        Area::Pages => file_name.ends_with(".json"),
+        Area::Images => true,
+        _ => false,
+    };
+
+    if !valid
+        || file_name.is_empty()
+        || file_name.starts_with('.')
+        || file_name.contains(|c| c == '/' || c == '\\')
+    {
+        return Err(anyhow!("不正なファイル名です: {}", file_name));
+    }
+
+    Ok(())
+}
+
+// 受け取ったファイルを保存する。新しい形式で保存されていて読み込めない場合はfalseを返す。
+// ほかの同期先にも送られるように更新済みにしておく
+async fn store(
+    storage: &dyn Storage,
+    area: Area,
+    file_name: &str,
+    contents: Vec<u8>,
+) -> Result<bool> {
+    check_file_name(area, file_name)?;
+
+    match area {
+        Area::Pages => {
+            let json = String::from_utf8(contents)?;
+            let wpage = match storage::parse_remote_week_page(file_name, &json)? {
+                Some(wpage) => wpage,
+                None => return Ok(false),
+            };
+
+            // 同期している間にこちらで書かれたページを失わないように統合する。
+            // 前回の内容はないので、どちらも編集したページは両方を残す
+            let wpage = if storage.exists(Area::Pages, file_name).await? {
+                let local = storage::read_week_page(storage, file_name).await?;
+                let (wpage, conflicts) = storage::merge(local, wpage, None);
+                for id in conflicts {
+                    println!(
+                        "ページ{}は両方の端末で編集されていたため、古い方をコピーとして残しました",
+                        id
+                    );
+                }
+                wpage
+            } else {
+                wpage
+            };
+
+            storage::write_week_page(storage, file_name, &wpage).await?;
+        }
+        _ => {
+            storage.write(Area::Images, file_name, &contents).await?;
+            storage::update_edited_entries(storage, |entries| {
+                entries.image_files.insert(file_name.to_string());
+            })
+            .await?;
+        }
+    }
+
+    Ok(true)
+}
+
+// 接続してきた端末の要求に応える
+async fn respond(storage: &dyn Storage, conn: &mut Connection) -> Result<()> {
+    loop {
+        let reply = match conn.receive().await? {
+            Message::GetManifest => Message::Manifest(manifest(storage).await?),
+            Message::Get { area, name } => {
+                check_file_name(area, &name)?;
+                let contents = storage.read(area, &name).await?;
+                Message::File {
+                    data: base64::encode(&contents),
+                }
+            }
+            Message::Put { area, name, data } => {
+                if store(storage, area, &name, base64::decode(&data)?).await? {
+                    println!("{}を受信しました", name);
+                }
+                Message::Stored
+            }
+            Message::Done => return Ok(()),
+            _ => return Err(unexpected()),
+        };
+
+        conn.send(&reply).await?;
+    }
+}
+
+// 他の端末からの同期を待ち受ける。
+// onceがtrueなら一度同期したら終了する。使い捨てのコードで待ち受けるときに使う
+pub async fn serve(
+    storage: &dyn Storage,
+    mut listener: TcpListener,
+    secret: &str,
+    once: bool,
+) -> Result<()> {
+    let mut failed_attempts = 0;
+
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        println!("{}から接続されました", addr);
+
+        let mut conn = Connection::new(stream);
+        match accept(&mut conn, secret).await {
+            Ok(true) => {}
+            Ok(false) => {
+                eprintln!("{}の認証に失敗しました", addr);
+
+                failed_attempts += 1;
+                if once && failed_attempts >= MAX_FAILED_ATTEMPTS {
+                    return Err(anyhow!("認証に{}回失敗したため終了します", failed_attempts));
+                }
+                continue;
+            }
+            Err(err) => {
+                eprintln!("{}の認証に失敗しました: {}", addr, err);
+                continue;
+            }
+        }
+
+        match respond(storage, &mut conn).await {
+            Ok(()) => {
+                println!("{}と同期しました", addr);
+                if once {
+                    return Ok(());
+                }
+            }
+            Err(err) => {
+                eprintln!("{}との同期に失敗しました: {}", addr, err);
+                let _ = conn
+                    .send(&Message::Error {
+                        message: err.to_string(),
+                    })
+                    .await;
+            }
+        }
+    }
+}
+
+async fn get(conn: &mut Connection, area: Area, file_name: &str) -> Result<Vec<u8>> {
+    conn.send(&Message::Get {
+        area,
+        name: file_name.to_string(),
+    })
+    .await?;
+
+    match conn.receive().await? {
+        Message::File { data } => Ok(base64::decode(&data)?),
+        _ => Err(unexpected()),
+    }
+}
+
+async fn put(conn: &mut Connection, area: Area, file_name: &str, contents: &[u8]) -> Result<()> {
+    conn.send(&Message::Put {
+        area,
+        name: file_name.to_string(),
+        data: base64::encode(contents),
+    })
+    .await?;
+
+    match conn.receive().await? {
+        Message::Stored => Ok(()),
+        _ => Err(unexpected()),
+    }
+}
+
+// 待ち受けている端末に接続して、互いに足りないファイルを送り合う
+pub async fn sync(storage: &dyn Storage, addr: &str, secret: &str) -> Result<()> {
+    let stream = TcpStream::connect(addr)
+        .await
+        .with_context(|| format!("{}に接続できませんでした", addr))?;
+    let mut conn = Connection::new(stream);
+
+    authenticate(&mut conn, secret).await?;
+
+    conn.send(&Message::GetManifest).await?;
+    let remote = match conn.receive().await? {
+        Message::Manifest(manifest) => manifest,
+        _ => return Err(unexpected()),
+    };
+    let local = manifest(storage).await?;
+
+    // ページファイルを同期
+
+    for (file_name, local_hash) in &local.pages {
+        match remote.pages.get(file_name) {
+            // 送信
+            None => {
+                println!("{}を送信しています...", file_name);
+
+                let contents = storage.read(Area::Pages, file_name).await?;
+                put(&mut conn, Area::Pages, file_name, &contents).await?;
+            }
+            // 統合して双方を更新
+            Some(remote_hash) if remote_hash != local_hash => {
+                println!("{}を更新しています...", file_name);
+
+                let contents = get(&mut conn, Area::Pages, file_name).await?;
+                if !store(storage, Area::Pages, file_name, contents).await? {
+                    continue;
+                }
+
+                let contents = storage.read(Area::Pages, file_name).await?;
+                put(&mut conn, Area::Pages, file_name, &contents).await?;
+            }
+            Some(_) => {}
+        }
+    }
+
+    // 受信
+    for file_name in remote.pages.keys() {
+        if local.pages.contains_key(file_name) {
+            continue;
+        }
+
+        let contents = get(&mut conn, Area::Pages, file_name).await?;
+        if store(storage, Area::Pages, file_name, contents).await? {
+            println!("{}を受信しました", file_name);
+        }
+    }
+
+    // 画像ファイルを同期
+
+    for file_name in local.images.difference(&remote.images) {
+        println!("{}を送信しています...", file_name);
+
+        let contents = storage.read(Area::Images, file_name).await?;
+        put(&mut conn, Area::Images, file_name, &contents).await?;
+    }
+
+    let renamed_images = storage.sync_state().await?.renamed_images;
+    for file_name in remote.images.difference(&local.images) {
+        // ゴミ箱にある画像と名前を変えた画像は受信しない
+        if storage.exists(Area::TrashImages, file_name).await? || renamed_images.contains(file_name)
+        {
+            continue;
+        }
+
+        let contents = get(&mut conn, Area::Images, file_name).await?;
+        store(storage, Area::Images, file_name, contents).await?;
+        println!("{}を受信しました", file_name);
+    }
+
+    conn.send(&Message::Done).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use crate::testing::page;
+    use chrono::{TimeZone, Utc};
+
+    async fn ids(storage: &dyn Storage) -> Vec<String> {
+        storage::list(storage, 10)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|page| page.id)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_sync_with_peer() {
+        // 同じ週と別の週にそれぞれページを書く
+        let server_storage = MemoryStorage::new();
+        storage::write(
+            &server_storage,
+            page("a", Utc.ymd(2020, 4, 1).and_hms(10, 0, 0)),
+        )
+        .await
+        .unwrap();
+        server_storage
+            .write(Area::Images, "image.png", b"image")
+            .await
+            .unwrap();
+
+        let client_storage = MemoryStorage::new();
+        storage::write(
+            &client_storage,
+            page("b", Utc.ymd(2020, 4, 2).and_hms(10, 0, 0)),
+        )
+        .await
+        .unwrap();
+        storage::write(
+            &client_storage,
+            page("c", Utc.ymd(2020, 5, 1).and_hms(10, 0, 0)),
+        )
+        .await
+        .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        let (served, synced) = tokio::join!(
+            serve(&server_storage, listener, "ABCD2345", true),
+            sync(&client_storage, &addr, "ABCD2345"),
+        );
+        served.unwrap();
+        synced.unwrap();
+
+        assert_eq!(vec!["c", "b", "a"], ids(&server_storage).await);
+        assert_eq!(vec!["c", "b", "a"], ids(&client_storage).await);
+        assert_eq!(
+            b"image".to_vec(),
+            client_storage
+                .read(Area::Images, "image.png")
+                .await
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reject_wrong_code() {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = async {
+            let (stream, _) = listener.accept().await.unwrap();
+            accept(&mut Connection::new(stream), "ABCD2345")
+                .await
+                .unwrap()
+        };
+        let client = async {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            authenticate(&mut Connection::new(stream), "WXYZ6789").await
+        };
+
+        let (accepted, authenticated) = tokio::join!(server, client);
+        assert!(!accepted);
+        assert!(authenticated.is_err());
+    }
+}
@@ -1,36 +1,64 @@
+mod filesystem;
+#[cfg(test)]
+mod memory;
+
 use std::cmp::Reverse;
 use std::collections::{HashMap, HashSet};
+use std::env;
 use std::mem;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 
-use anyhow::Result;
+use anyhow::{anyhow, Context as _, Result};
+use async_trait::async_trait;
 use chrono::{Date, DateTime, Datelike, Duration, Utc, Weekday};
-use tokio::fs::{self, DirEntry};
-use tokio::stream::StreamExt;
+use futures::stream::{self, StreamExt};
+use indicatif::{HumanBytes, ProgressBar, ProgressStyle};
+use tokio::fs;
 use uuid::Uuid;
 
-use crate::dropbox;
-use crate::dropbox::AccessToken;
-use crate::page::{Page, WeekPage, WeekPageV1};
+use crate::backend::{RemoteFile, SyncBackend};
+use crate::crypto;
+use crate::migration::{self, NewerVersionError};
+use crate::page::{self, Page, Tombstone, WeekPage};
+
+pub use filesystem::FsStorage;
+#[cfg(test)]
+pub use memory::MemoryStorage;
 
-#[derive(Debug, Serialize, Deserialize)]
-struct EditedEntries {
-    page_files: HashSet<String>,
-    image_files: HashSet<String>,
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct EditedEntries {
+    pub page_files: HashSet<String>,
+    pub image_files: HashSet<String>,
 }
 
 impl EditedEntries {
-    fn new() -> Self {
+    pub fn new() -> Self {
         EditedEntries {
             page_files: HashSet::new(),
             image_files: HashSet::new(),
         }
     }
+}
 
-    fn clear(&mut self) {
-        self.page_files.clear();
-        self.image_files.clear();
-    }
+// 前回の同期で確認したリモートのファイルのリビジョン。リモートでの変更を検出するのに使う
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SyncState {
+    // リモートのパスからリビジョンへの対応
+    pub revisions: HashMap<String, String>,
+    // カーソルに対応した同期先で前回取得したフォルダの一覧。次回は変更だけを取得する
+    #[serde(default)]
+    pub listings: HashMap<String, Listing>,
+    // 名前を変えた画像の古い名前。リモートに残っていてもダウンロードしない
+    #[serde(default)]
+    pub renamed_images: HashSet<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Listing {
+    pub cursor: String,
+    // ファイル名からファイルの情報への対応
+    pub files: HashMap<String, RemoteFile>,
 }
 
 #[derive(Debug)]
@@ -41,11 +69,123 @@ struct FileState {
 }
 
 pub const PAGE_DIR: &str = "pages";
-pub const PAGES_DIR_ON_DROPBOX: &str = "/pages";
+pub const PAGES_DIR_ON_REMOTE: &str = "/pages";
 pub const IMAGE_DIR: &str = "images";
-pub const IMAGE_DIR_ON_DROPBOX: &str = "/images";
+pub const IMAGE_DIR_ON_REMOTE: &str = "/images";
+pub const ENCRYPTED_PAGES_DIR_ON_REMOTE: &str = "/encrypted/pages";
+pub const ENCRYPTED_IMAGE_DIR_ON_REMOTE: &str = "/encrypted/images";
+pub const SYNC_KEY_FILE_ON_REMOTE: &str = "/encrypted/key.json";
 pub const BACKUP_DIR_PREFIX: &str = "backup";
+// バックアップする種類。どちらもページの形式の変換で書き換えられる
+const BACKUP_AREAS: &[Area] = &[Area::Pages, Area::TrashPages];
 pub const EDITED_ENTRIES_FILE: &str = "edited_entries.json";
+pub const SYNC_STATE_FILE: &str = "sync_state.json";
+pub const SYNC_BASE_DIR: &str = "sync_base";
+pub const TRASH_DIR: &str = "trash";
+// 以前のバージョンが復号した画像を書き出していたディレクトリ
+pub const VIEWABLE_IMAGE_DIR: &str = "viewable_images";
+// 同時に転送するファイルの数
+const MAX_CONCURRENT_TRANSFERS: usize = 4;
+
+// 保存するファイルの種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Area {
+    // 週ごとのファイル
+    Pages,
+    Images,
+    TrashPages,
+    TrashImages,
+    // 前回の同期での週ごとのファイル。統合するときに、どちらで編集されたかを判断するのに使う
+    SyncBase,
+}
+
+// ページや画像の保存先。暗号化する場合は実装側で行う
+#[async_trait]
+pub trait Storage: Send + Sync {
+    // ファイル名をすべて取得する。順番は決まっていない
+    async fn list(&self, area: Area) -> Result<Vec<String>>;
+    async fn exists(&self, area: Area, file_name: &str) -> Result<bool>;
+    async fn read(&self, area: Area, file_name: &str) -> Result<Vec<u8>>;
+    async fn write(&self, area: Area, file_name: &str, contents: &[u8]) -> Result<()>;
+    // 一時ファイルの内容を書き込む。一時ファイルは移動されるか削除される
+    async fn write_from_file(&self, area: Area, file_name: &str, source: &Path) -> Result<()>;
+    async fn remove(&self, area: Area, file_name: &str) -> Result<()>;
+    // 別の種類へ移動することもできる
+    async fn rename(&self, from: Area, from_name: &str, to: Area, to_name: &str) -> Result<()>;
+
+    // 同期していない更新済みのファイル
+    async fn edited_entries(&self) -> Result<EditedEntries>;
+    async fn write_edited_entries(&self, entries: &EditedEntries) -> Result<()>;
+
+    async fn sync_state(&self) -> Result<SyncState>;
+    async fn write_sync_state(&self, state: &SyncState) -> Result<()>;
+
+    // ページとゴミ箱のページをバックアップして、復元に使うIDを返す
+    async fn create_pages_backup(&self) -> Result<u32>;
+    // バックアップから復元して、バックアップを削除する
+    async fn rollback(&self, id: u32) -> Result<()>;
+    async fn remove_pages_backup(&self, id: u32) -> Result<()>;
+    // バックアップの場所。表示に使う
+    fn pages_backup_location(&self, id: u32) -> String;
+
+    // 保存時の暗号化。対応していない保存先では常に暗号化されていない
+    fn is_encrypted(&self) -> bool {
+        false
+    }
+
+    fn enable_encryption(&self, _passphrase: &str) -> Result<()> {
+        Err(anyhow!("この保存先は暗号化に対応していません"))
+    }
+
+    fn disable_encryption(&self) -> Result<()> {
+        Ok(())
+    }
+
+    // 暗号化されていないファイルをすべて暗号化して、暗号化した数を返す
+    async fn encrypt_all(&self) -> Result<usize> {
+        Ok(0)
+    }
+
+    // 暗号化されたファイルをすべて復号して、復号した数を返す
+    async fn decrypt_all(&self) -> Result<usize> {
+        Ok(0)
+    }
+
+    // 保存先の外から渡されたデータを、暗号化されていれば復号する
+    fn decrypt_if_needed(&self, data: Vec<u8>) -> Result<Vec<u8>> {
+        Ok(data)
+    }
+
+    // 保存先の外に書き出すデータを、暗号化が有効なら暗号化する
+    fn encrypt_if_enabled(&self, contents: &[u8]) -> Result<Vec<u8>> {
+        Ok(contents.to_vec())
+    }
+
+    // ブラウザで表示できる画像のディレクトリを取得する。
+    // 使われている画像だけを一時ディレクトリに書き出す
+    async fn viewable_image_dir(&self, file_names: &[String], temp_dir: &Path) -> Result<PathBuf> {
+        write_images_to(self, file_names, temp_dir).await
+    }
+}
+
+// 画像を一時ディレクトリに書き出す。暗号化されていれば復号される
+async fn write_images_to<S: Storage + ?Sized>(
+    storage: &S,
+    file_names: &[String],
+    temp_dir: &Path,
+) -> Result<PathBuf> {
+    let image_dir = temp_dir.join(IMAGE_DIR);
+    fs::create_dir_all(&image_dir).await?;
+    for file_name in file_names {
+        if storage.exists(Area::Images, file_name).await? {
+            let image = storage.read(Area::Images, file_name).await?;
+            fs::write(image_dir.join(file_name), image).await?;
+        }
+    }
+
+    Ok(image_dir)
+}
 
 // 日曜日と土曜日の日付を取得
 fn find_week(day: Date<Utc>) -> (Date<Utc>, Date<Utc>) {
@@ -68,46 +208,86 @@ fn find_week(day: Date<Utc>) -> (Date<Utc>, Date<Utc>) {
     (begin, end)
 }
 
-fn generate_page_filepath(directory: &Path, date: Date<Utc>) -> PathBuf {
+fn generate_week_file_name(date: Date<Utc>) -> String {
     let (week_begin, week_end) = find_week(date);
-    let filename = format!(
+    format!(
         "{}-{}.json",
         week_begin.format("%Y-%m-%d"),
         week_end.format("%Y-%m-%d")
-    );
-
-    directory.join(PAGE_DIR).join(&filename)
+    )
 }
 
-async fn get_edited_entries(directory: &Path) -> Result<EditedEntries> {
-    let file_path = directory.join(EDITED_ENTRIES_FILE);
-
-    if file_path.exists() {
-        let json = fs::read_to_string(&file_path).await?;
-        let entries: EditedEntries = serde_json::from_str(&json)?;
-        Ok(entries)
-    } else {
-        Ok(EditedEntries::new())
-    }
-}
-
-async fn update_edited_entries<F>(directory: &Path, edit: F) -> Result<()>
+pub async fn update_edited_entries<F>(storage: &dyn Storage, edit: F) -> Result<()>
 where
     F: FnOnce(&mut EditedEntries),
 {
-    let mut entries = get_edited_entries(directory).await?;
+    let mut entries = storage.edited_entries().await?;
 
     edit(&mut entries);
 
-    let file_path = directory.join(EDITED_ENTRIES_FILE);
-    let json = serde_json::to_string(&entries)?;
-    fs::write(&file_path, json).await?;
+    storage.write_edited_entries(&entries).await?;
+
+    Ok(())
+}
+
+// 週ごとのファイルの名前をファイル名の降順で取得する
+pub async fn week_file_names(storage: &dyn Storage) -> Result<Vec<String>> {
+    let mut file_names: Vec<String> = storage
+        .list(Area::Pages)
+        .await?
+        .into_iter()
+        .filter(|file_name| file_name.ends_with(".json"))
+        .collect();
+
+    file_names.sort_by(|a, b| b.cmp(a));
+
+    Ok(file_names)
+}
+
+pub async fn read_to_string(storage: &dyn Storage, area: Area, file_name: &str) -> Result<String> {
+    let data = storage.read(area, file_name).await?;
+    Ok(String::from_utf8(data)?)
+}
+
+pub async fn read_week_page(storage: &dyn Storage, file_name: &str) -> Result<WeekPage> {
+    let json = read_to_string(storage, Area::Pages, file_name).await?;
+    let week_page = migration::parse_week_page(&json)?;
+    Ok(week_page)
+}
+
+// 一時ファイルに書き込んでから置き換える
+pub async fn write_atomically(file_path: &Path, contents: &[u8]) -> Result<()> {
+    let mut temp_file_name = file_path.file_name().unwrap().to_os_string();
+    temp_file_name.push(".tmp");
+    let temp_file_path = file_path.with_file_name(temp_file_name);
+
+    fs::write(&temp_file_path, contents).await?;
+    fs::rename(&temp_file_path, file_path).await?;
 
     Ok(())
 }
 
-pub async fn write(directory: &Path, page: Page) -> Result<()> {
-    let filepath = generate_page_filepath(directory, Utc::today());
+pub async fn write_week_page(
+    storage: &dyn Storage,
+    file_name: &str,
+    week_page: &WeekPage,
+) -> Result<()> {
+    update_edited_entries(storage, |entries| {
+        entries.page_files.insert(file_name.to_string());
+    })
+    .await?;
+
+    let json = serde_json::to_string(week_page)?;
+    storage
+        .write(Area::Pages, file_name, json.as_bytes())
+        .await?;
+
+    Ok(())
+}
+
+pub async fn write(storage: &dyn Storage, page: Page) -> Result<()> {
+    // ページが作成された週のファイルに書き込む
+    let file_name = generate_week_file_name(page.created_at.date());
 
     // なぜか追記される
     // let file = OpenOptions::new()
@@ -116,11 +296,10 @@ pub async fn write(directory: &Path, page: Page) -> Result<()> {
     //     .create(true)
     //     .open(&filepath)?;
 
-    let exists = filepath.exists();
+    let exists = storage.exists(Area::Pages, &file_name).await?;
     let mut week_page = if exists {
-        // ファイルが存在したら今週のページを読み込む
-        let json = fs::read_to_string(&filepath).await?;
-        serde_json::from_str(&json)?
+        // ファイルが存在したらその週のページを読み込む
+        read_week_page(storage, &file_name).await?
     } else {
         // ファイルが存在しなかったらWeekPageを作成
         WeekPage::new()
@@ -132,7 +311,7 @@ pub async fn write(directory: &Path, page: Page) -> Result<()> {
     match week_page
         .pages
         .iter()
-        .position(|old_page| page.created_at == old_page.created_at)
+        .position(|old_page| page.id == old_page.id)
     {
         // ページが存在したら更新
         Some(pos) => {
@@ -142,60 +321,164 @@ pub async fn write(directory: &Path, page: Page) -> Result<()> {
         None => week_page.pages.push(page),
     };
 
-    update_edited_entries(directory, |entries| {
-        entries
-            .page_files
-            .insert(filepath.file_name().unwrap().to_string_lossy().to_string());
+    write_week_page(storage, &file_name, &week_page).await?;
+
+    Ok(())
+}
+
+// IDが一致するページを削除して、削除したページを返す
+pub async fn delete(storage: &dyn Storage, id: &str) -> Result<Option<Page>> {
+    for file_name in week_file_names(storage).await? {
+        let mut week_page = read_week_page(storage, &file_name).await?;
+
+        let pos = match week_page.pages.iter().position(|page| page.id == id) {
+            Some(pos) => pos,
+            None => continue,
+        };
+
+        let page = week_page.pages.remove(pos);
+        let deleted_at = Utc::now();
+
+        // 同期したときに他の端末でも削除されるように削除記録を残す
+        week_page.deleted.push(Tombstone {
+            id: page.id.clone(),
+            deleted_at,
+        });
+
+        // syncコマンドでアップロードされるようにuploaded_atを消す
+        week_page.uploaded_at = None;
+
+        // 完全に消す前にゴミ箱へ移動する
+        move_to_trash(storage, &page, deleted_at).await?;
+
+        write_week_page(storage, &file_name, &week_page).await?;
+
+        return Ok(Some(page));
+    }
+
+    Ok(None)
+}
+
+// ページの作成日時を変更する。週が変わる場合は新しい週のファイルへ移動する
+pub async fn retime(storage: &dyn Storage, id: &str, created_at: DateTime<Utc>) -> Result<Page> {
+    for file_name in week_file_names(storage).await? {
+        let mut week_page = read_week_page(storage, &file_name).await?;
+
+        let pos = match week_page.pages.iter().position(|page| page.id == id) {
+            Some(pos) => pos,
+            None => continue,
+        };
+
+        let mut page = week_page.pages.remove(pos);
+        let now = Utc::now();
+
+        // 画像のファイル名を新しい作成日時のものに変更する
+        let old_prefix = page::generate_image_prefix(&page.created_at);
+        let new_prefix = page::generate_image_prefix(&created_at);
+        let (text, images) = page::convert_image_paths_in_text(&page.text, |s| {
+            if s.starts_with(&old_prefix) {
+                format!("{}{}", new_prefix, &s[old_prefix.len()..])
+            } else {
+                s.to_string()
+            }
+        });
+        let text = text.to_string();
+
+        for (old_file_name, new_file_name) in images {
+            let old_file_name = old_file_name.to_string_lossy().to_string();
+            if old_file_name != new_file_name {
+                rename_image(storage, &old_file_name, &new_file_name).await?;
+            }
+        }
+
+        page.text = text;
+        page.created_at = created_at;
+        page.updated_at.push(now);
+
+        if generate_week_file_name(created_at.date()) != file_name {
+            // 古い週のリモートのファイルからページが戻ってこないように削除記録を残す
+            week_page.deleted.push(Tombstone {
+                id: page.id.clone(),
+                deleted_at: now,
+            });
+            week_page.uploaded_at = None;
+
+            write_week_page(storage, &file_name, &week_page).await?;
+        }
+
+        write(storage, page.clone()).await?;
+
+        return Ok(page);
+    }
+
+    Err(anyhow!("ID `{}` のページが見つかりませんでした", id))
+}
+
+async fn rename_image(storage: &dyn Storage, from: &str, to: &str) -> Result<()> {
+    if !storage.exists(Area::Images, from).await? {
+        return Ok(());
+    }
+
+    storage.rename(Area::Images, from, Area::Images, to).await?;
+
+    update_edited_entries(storage, |entries| {
+        entries.image_files.remove(from);
+        entries.image_files.insert(to.to_string());
     })
     .await?;
 
-    let json = serde_json::to_string(&week_page)?;
-    fs::write(&filepath, &json).await?;
+    // 古い名前の画像はリモートに残るので、ダウンロードし直さないように記録する
+    let mut sync_state = storage.sync_state().await?;
+    sync_state.renamed_images.remove(to);
+    sync_state.renamed_images.insert(from.to_string());
+    storage.write_sync_state(&sync_state).await?;
 
     Ok(())
 }
 
-pub async fn write_image(directory: &Path, image_path: &Path, file_name: &str) -> Result<()> {
-    let dest = directory.join(IMAGE_DIR).join(file_name);
-
-    update_edited_entries(directory, |entries| {
-        entries
-            .image_files
-            .insert(dest.file_name().unwrap().to_string_lossy().to_string());
+pub async fn write_image(storage: &dyn Storage, image_path: &Path, file_name: &str) -> Result<()> {
+    update_edited_entries(storage, |entries| {
+        entries.image_files.insert(file_name.to_string());
     })
     .await?;
 
-    fs::copy(image_path, dest).await?;
+    let image = fs::read(image_path).await?;
+    storage.write(Area::Images, file_name, &image).await?;
 
     Ok(())
 }
 
-pub async fn list(directory: &Path, limit: u32) -> Result<Vec<Page>> {
-    list_with_filter(directory, limit, |_| true).await
+pub async fn list(storage: &dyn Storage, limit: u32) -> Result<Vec<Page>> {
+    list_with_filter(storage, limit, |_| true).await
 }
 
-pub async fn list_with_filter<F>(directory: &Path, limit: u32, filter: F) -> Result<Vec<Page>>
+pub async fn list_with_filter<F>(storage: &dyn Storage, limit: u32, filter: F) -> Result<Vec<Page>>
 where
     F: Fn(&Page) -> bool,
 {
-    // ページが格納されているディレクトリのファイルをすべて取得する
-    let mut entries: Vec<DirEntry> = fs::read_dir(directory.join(PAGE_DIR))
-        .await?
-        .filter(|entry| entry.is_ok())
-        .map(|entry| entry.unwrap())
-        .collect()
-        .await;
+    list_with_filter_map(storage, limit, |page| {
+        Ok(if filter(&page) { Some(page) } else { None })
+    })
+    .await
+}
 
-    // ファイル名で降順にソート
-    entries.sort_by_key(|entry| Reverse(entry.file_name()));
+// ページを変換しながら絞り込む。Noneを返したページは含めない
+pub async fn list_with_filter_map<F>(
+    storage: &dyn Storage,
+    limit: u32,
+    mut filter_map: F,
+) -> Result<Vec<Page>>
+where
+    F: FnMut(Page) -> Result<Option<Page>>,
+{
+    // 週ごとのファイルをファイル名の降順ですべて取得する
+    let file_names = week_file_names(storage).await?;
 
     let mut pages: Vec<Page> = Vec::new();
     let mut count = 0u32;
 
-    'a: for entry in entries {
-        let json = fs::read_to_string(entry.path()).await?;
-
-        let mut week_page: WeekPage = serde_json::from_str(&json)?;
+    'a: for file_name in file_names {
+        let mut week_page = read_week_page(storage, &file_name).await?;
         week_page.pages.sort_by_key(|page| Reverse(page.created_at));
 
         for page in week_page.pages {
@@ -203,7 +486,7 @@ where
                 break 'a;
             }
 
-            if filter(&page) {
+            if let Some(page) = filter_map(page)? {
                 pages.push(page);
                 count += 1;
             }
@@ -214,7 +497,7 @@ where
 }
 
 pub async fn get_week_page_range(
-    directory: &Path,
+    storage: &dyn Storage,
     start: &DateTime<Utc>,
     end: &DateTime<Utc>,
 ) -> Result<Vec<WeekPage>> {
@@ -225,24 +508,23 @@ pub async fn get_week_page_range(
     let mut date = start.date();
     let end = end.date();
     let mut wpages = Vec::with_capacity((end - date).num_weeks() as usize);
-    let mut last_file_path = None;
+    let mut last_file_name = None;
 
     while date <= end {
-        let file_path = generate_page_filepath(directory, date);
-        let json = fs::read_to_string(&file_path).await?;
-        let wpage: WeekPage = serde_json::from_str(&json)?;
-        wpages.push(wpage);
+        // ページを書いていない週はファイルが存在しない
+        let file_name = generate_week_file_name(date);
+        if storage.exists(Area::Pages, &file_name).await? {
+            wpages.push(read_week_page(storage, &file_name).await?);
+        }
 
         date = date + Duration::days(7);
-        last_file_path = Some(file_path.clone());
+        last_file_name = Some(file_name);
     }
 
-    let last_file_path = last_file_path.unwrap();
-    let file_path = generate_page_filepath(directory, end);
-    if last_file_path != file_path {
-        let json = fs::read_to_string(&file_path).await?;
-        let wpage: WeekPage = serde_json::from_str(&json)?;
-        wpages.push(wpage);
+    let last_file_name = last_file_name.unwrap();
+    let file_name = generate_week_file_name(end);
+    if last_file_name != file_name && storage.exists(Area::Pages, &file_name).await? {
+        wpages.push(read_week_page(storage, &file_name).await?);
     }
 
     Ok(wpages)
@@ -259,23 +541,145 @@ pub fn integrate(wpage1: WeekPage, wpage2: WeekPage) -> WeekPage {
         }
     }
 
+    // 削除記録を統合する。同じIDの記録は新しい方を残す
+    for tombstone in wpage1.deleted {
+        match new_wpage
+            .deleted
+            .iter_mut()
+            .find(|tombstone2| tombstone.id == tombstone2.id)
+        {
+            Some(tombstone2) => {
+                if tombstone.deleted_at > tombstone2.deleted_at {
+                    tombstone2.deleted_at = tombstone.deleted_at;
+                }
+            }
+            None => new_wpage.deleted.push(tombstone),
+        }
+    }
+
+    // どちらかで削除されたページを取り除く
+    let deleted = &new_wpage.deleted;
+    new_wpage
+        .pages
+        .retain(|page| !page::is_deleted(deleted, page));
+
     new_wpage
 }
 
+// 同じIDのページのどちらを残すか
+#[derive(Debug, PartialEq)]
+enum PageChoice {
+    Local,
+    Remote,
+    // 両方で編集されている
+    Conflict,
+}
+
+// pageがancestorを編集したものか。更新日時は編集するたびに追加される
+fn is_descendant(page: &Page, ancestor: &Page) -> bool {
+    page.updated_at.len() > ancestor.updated_at.len()
+        && page.updated_at.starts_with(&ancestor.updated_at)
+}
+
+// 前回の同期での内容と比べて、編集された方を選ぶ。
+// 前回の内容がなければ更新日時の履歴で判断する
+fn choose_page(local: &Page, remote: &Page, base: Option<&Page>) -> PageChoice {
+    if local == remote {
+        return PageChoice::Remote;
+    }
+
+    if let Some(base) = base {
+        if local == base {
+            return PageChoice::Remote;
+        }
+        if remote == base {
+            return PageChoice::Local;
+        }
+    }
+
+    if is_descendant(local, remote) {
+        PageChoice::Local
+    } else if is_descendant(remote, local) {
+        PageChoice::Remote
+    } else {
+        PageChoice::Conflict
+    }
+}
+
+// 両方で編集されたページの一方を、別のIDのページとして残す
+fn conflict_copy(page: &Page) -> Page {
+    let mut copy = page.clone();
+    copy.id = Uuid::new_v4().to_string();
+
+    // 隠しページのタイトルは暗号化されているので変更できない
+    if !copy.is_sealed() {
+        copy.title = format!("{} (競合したコピー)", copy.title);
+    }
+
+    copy
+}
+
+// 前回の同期での内容を使って、ページごとに新しい方を残すように統合する。
+// 両方で編集されたページは新しい方を残して、古い方をコピーとして追加する。
+// 統合したページと、競合したページのIDを返す
+pub fn merge(
+    local: WeekPage,
+    mut remote: WeekPage,
+    base: Option<&WeekPage>,
+) -> (WeekPage, Vec<String>) {
+    let mut copies = Vec::new();
+    let mut conflicts = Vec::new();
+
+    for remote_page in &mut remote.pages {
+        let local_page = match local.pages.iter().find(|page| page.id == remote_page.id) {
+            Some(page) => page,
+            None => continue,
+        };
+        let base_page =
+            base.and_then(|base| base.pages.iter().find(|page| page.id == remote_page.id));
+
+        match choose_page(local_page, remote_page, base_page) {
+            PageChoice::Local => *remote_page = local_page.clone(),
+            PageChoice::Remote => {}
+            PageChoice::Conflict => {
+                if local_page.last_modified() > remote_page.last_modified() {
+                    copies.push(conflict_copy(remote_page));
+                    *remote_page = local_page.clone();
+                } else {
+                    copies.push(conflict_copy(local_page));
+                }
+                conflicts.push(remote_page.id.clone());
+            }
+        }
+    }
+
+    // 同じIDのページは選んだ方に置き換えてあるので、残りはintegrateと同じ
+    let mut wpage = integrate(local, remote);
+    wpage.pages.extend(copies);
+
+    (wpage, conflicts)
+}
+
+// リモートで変更されたファイルは、ローカルで編集されたファイルと同じように統合する。
+// リモートにないファイルは同期先を切り替えた場合などに編集していなくてもアップロードする
 fn get_file_map<'a, IR>(
-    local_files_dir: &Path,
+    local_files: &HashSet<String>,
     edited_files: &HashSet<String>,
+    changed_on_remote: &HashSet<String>,
     files_on_remote: IR,
 ) -> Result<HashMap<String, FileState>>
 where
     IR: Iterator<Item = &'a str>,
 {
     let mut result = HashMap::default();
+    let mut remote_files = HashSet::new();
 
     for file_name in files_on_remote {
-        let exists_on_local = local_files_dir.join(file_name).exists();
+        remote_files.insert(file_name);
+
+        let exists_on_local = local_files.contains(file_name);
         if exists_on_local {
-            if edited_files.contains(file_name) {
+            if edited_files.contains(file_name) || changed_on_remote.contains(file_name) {
                 result.insert(
                     file_name.to_string(),
                     FileState {
@@ -300,259 +704,1728 @@ where
     }
 
     for file_name in edited_files {
-        assert!(local_files_dir.join(file_name).exists());
+        assert!(local_files.contains(file_name));
+    }
 
-        result.entry(file_name.to_string()).or_insert(FileState {
-            exists_on_local: true,
-            exists_on_remote: false,
-            is_edited: true,
-        });
+    for file_name in local_files {
+        if !remote_files.contains(file_name.as_str()) {
+            result.insert(
+                file_name.to_string(),
+                FileState {
+                    exists_on_local: true,
+                    exists_on_remote: false,
+                    is_edited: true,
+                },
+            );
+        }
     }
 
     Ok(result)
 }
 
-pub async fn sync(
-    directory: &Path,
-    client: &reqwest::Client,
-    access_token: &AccessToken,
+// リモートのファイルを読み込む。新しい形式で保存されていて読み込めない場合はNoneを返す
+pub fn parse_remote_week_page(file_name: &str, json: &str) -> Result<Option<WeekPage>> {
+    match migration::parse_week_page(json) {
+        Ok(wpage) => Ok(Some(wpage)),
+        Err(err) => match err.downcast_ref::<NewerVersionError>() {
+            Some(err) => {
+                eprintln!("{}は{}", file_name, err);
+                Ok(None)
+            }
+            None => Err(err.context(format!("{}を読み込めませんでした", file_name))),
+        },
+    }
+}
+
+// 前回の同期での週ごとのファイルを読み込む
+async fn read_sync_base(storage: &dyn Storage, file_name: &str) -> Result<Option<WeekPage>> {
+    if !storage.exists(Area::SyncBase, file_name).await? {
+        return Ok(None);
+    }
+
+    let json = read_to_string(storage, Area::SyncBase, file_name).await?;
+    Ok(Some(migration::parse_week_page(&json)?))
+}
+
+// リモートに鍵ファイルがあれば、パスフレーズを入力させて同期用の鍵を取得する
+async fn unlock_sync_key(backend: &dyn SyncBackend) -> Result<Option<crypto::Key>> {
+    if backend.metadata(SYNC_KEY_FILE_ON_REMOTE).await?.is_none() {
+        return Ok(None);
+    }
+
+    let json = backend.get(SYNC_KEY_FILE_ON_REMOTE).await?;
+    let key_file: crypto::KeyFile = serde_json::from_slice(&json)?;
+
+    let passphrase =
+        crypto::read_passphrase(crypto::SYNC_PASSPHRASE_ENV, "同期用のパスフレーズ: ")?;
+    Ok(Some(key_file.unlock(&passphrase)?))
+}
+
+// 同期用の鍵を取得する。リモートに鍵ファイルがなければ作成する
+async fn prepare_sync_key(storage: &dyn Storage, backend: &dyn SyncBackend) -> Result<crypto::Key> {
+    if let Some(key) = unlock_sync_key(backend).await? {
+        return Ok(key);
+    }
+
+    println!("同期用のパスフレーズを設定します。他の端末でも同じパスフレーズを入力してください");
+    let passphrase =
+        crypto::read_new_passphrase(crypto::SYNC_PASSPHRASE_ENV, "同期用のパスフレーズ: ")?;
+    let (key_file, key) = crypto::KeyFile::new(&passphrase)?;
+
+    // 他の端末が同時に作成した鍵ファイルを上書きしないようにする
+    let json = serde_json::to_string(&key_file)?;
+    if backend
+        .put_new(SYNC_KEY_FILE_ON_REMOTE, json.into_bytes())
+        .await?
+        .is_none()
+    {
+        return Err(anyhow!(
+            "他の端末で同期用のパスフレーズが設定されました。もう一度実行してください"
+        ));
+    }
+
+    // 暗号化した同期先にはまだ何もないので、すべてのファイルをアップロードする
+    let page_files = week_file_names(storage).await?;
+    let image_files = storage.list(Area::Images).await?;
+    update_edited_entries(storage, |entries| {
+        entries.page_files.extend(page_files);
+        entries.image_files.extend(image_files);
+    })
+    .await?;
+
+    Ok(key)
+}
+
+// リモートでのファイル名。暗号化する場合はどの週のファイルかわからないようにする
+fn remote_file_name(key: Option<&crypto::Key>, file_name: &str) -> String {
+    match key {
+        Some(key) => crypto::obfuscate_file_name(key, file_name),
+        None => file_name.to_string(),
+    }
+}
+
+// リモートのファイル名からローカルのファイル名を引けるようにする
+fn local_file_names_by_remote(
+    key: Option<&crypto::Key>,
+    local_files: &HashSet<String>,
+) -> HashMap<String, String> {
+    local_files
+        .iter()
+        .map(|file_name| (remote_file_name(key, file_name), file_name.clone()))
+        .collect()
+}
+
+// 前回の同期からリビジョンが変わったリモートのファイルを取得する。
+// リビジョンを持たない同期先では常に空になる
+fn changed_on_remote(
+    sync_state: &SyncState,
+    dir_on_remote: &str,
+    files_on_remote: &[RemoteFile],
+    local_file_names_by_remote: &HashMap<String, String>,
+) -> HashSet<String> {
+    files_on_remote
+        .iter()
+        .filter(|f| match &f.revision {
+            Some(revision) => {
+                let path = format!("{}/{}", dir_on_remote, f.name);
+                sync_state.revisions.get(&path) != Some(revision)
+            }
+            None => false,
+        })
+        .map(|f| {
+            local_file_names_by_remote
+                .get(&f.name)
+                .unwrap_or(&f.name)
+                .clone()
+        })
+        .collect()
+}
+
+// リモートのファイルの一覧を取得する。前回の一覧とカーソルがあれば、変更だけを取得して反映する
+async fn list_remote(
+    backend: &dyn SyncBackend,
+    sync_state: &mut SyncState,
+    dir_on_remote: &str,
+) -> Result<Vec<RemoteFile>> {
+    if let Some(mut listing) = sync_state.listings.remove(dir_on_remote) {
+        if let Some(changes) = backend.list_changes(dir_on_remote, &listing.cursor).await? {
+            for file_name in changes.deleted {
+                listing.files.remove(&file_name);
+            }
+            for f in changes.updated {
+                listing.files.insert(f.name.clone(), f);
+            }
+            listing.cursor = changes.cursor;
+
+            let files = listing.files.values().cloned().collect();
+            sync_state
+                .listings
+                .insert(dir_on_remote.to_string(), listing);
+            return Ok(files);
+        }
+    }
+
+    // 初回の同期か、カーソルが無効になった場合はすべて取得する
+    let (files, cursor) = backend.list_with_cursor(dir_on_remote).await?;
+    if let Some(cursor) = cursor {
+        let listing = Listing {
+            cursor,
+            files: files.iter().map(|f| (f.name.clone(), f.clone())).collect(),
+        };
+        sync_state
+            .listings
+            .insert(dir_on_remote.to_string(), listing);
+    }
+
+    Ok(files)
+}
+
+// 同期先が内容のハッシュを返す場合は、リビジョンの代わりにローカルのページファイルと内容を比較する。
+// 前回の同期の記録がなくても、他の端末で編集されたファイルを見つけられる
+async fn compare_content_hashes(
+    storage: &dyn Storage,
+    backend: &dyn SyncBackend,
+    files_on_remote: &[RemoteFile],
+    local_files: &HashSet<String>,
+    changed_files: &mut HashSet<String>,
 ) -> Result<()> {
-    dropbox::create_folder(client, access_token, PAGES_DIR_ON_DROPBOX).await?;
-    dropbox::create_folder(client, access_token, IMAGE_DIR_ON_DROPBOX).await?;
+    for f in files_on_remote {
+        let content_hash = match &f.content_hash {
+            Some(content_hash) => content_hash,
+            None => continue,
+        };
+        if !local_files.contains(&f.name) {
+            continue;
+        }
 
-    let edited_entries = get_edited_entries(directory).await?;
+        let contents = storage.read(Area::Pages, &f.name).await?;
+        if backend.content_hash(&contents).as_ref() == Some(content_hash) {
+            changed_files.remove(&f.name);
+        } else {
+            changed_files.insert(f.name.clone());
+        }
+    }
 
-    // ページファイルを同期
+    Ok(())
+}
 
-    let page_files_on_remote =
-        dropbox::list_files(client, access_token, PAGES_DIR_ON_DROPBOX).await?;
+// 一覧で確認したリビジョンを記録する
+fn record_revisions(
+    sync_state: &mut SyncState,
+    dir_on_remote: &str,
+    files_on_remote: &[RemoteFile],
+) {
+    for f in files_on_remote {
+        if let Some(revision) = &f.revision {
+            sync_state
+                .revisions
+                .insert(format!("{}/{}", dir_on_remote, f.name), revision.clone());
+        }
+    }
+}
 
-    let page_dir = directory.join(PAGE_DIR);
-    let file_map = get_file_map(
-        &page_dir,
-        &edited_entries.page_files,
-        page_files_on_remote.iter().map(|f| f.name.as_ref()),
+fn seal(key: Option<&crypto::Key>, file_name: &str, contents: Vec<u8>) -> Result<Vec<u8>> {
+    match key {
+        Some(key) => crypto::encrypt_with_file_name(key, file_name, &contents),
+        None => Ok(contents),
+    }
+}
+
+// ダウンロードしたファイルを復号して、ローカルのファイル名と内容を返す
+fn open(
+    key: Option<&crypto::Key>,
+    remote_file_name: &str,
+    data: Vec<u8>,
+) -> Result<(String, Vec<u8>)> {
+    match key {
+        Some(key) => {
+            let (file_name, contents) = crypto::decrypt_with_file_name(key, &data)
+                .with_context(|| format!("{}を復号できませんでした", remote_file_name))?;
+
+            // 別のファイルと入れ替えられていないか確かめる
+            if crypto::obfuscate_file_name(key, &file_name) != remote_file_name {
+                return Err(anyhow!(
+                    "{}の内容がファイル名と一致しません",
+                    remote_file_name
+                ));
+            }
+
+            Ok((file_name, contents))
+        }
+        None => Ok((remote_file_name.to_string(), data)),
+    }
+}
+
+// ローカルにもあるリモートのページファイルを読み込む。
+// 新しい形式で保存されていて読み込めない場合はNoneを返す
+async fn read_remote_week_page(
+    backend: &dyn SyncBackend,
+    key: Option<&crypto::Key>,
+    pages_dir_on_remote: &str,
+    file_name: &str,
+) -> Result<Option<WeekPage>> {
+    let remote_file_name = remote_file_name(key, file_name);
+    let path_to_remote = format!("{}/{}", pages_dir_on_remote, remote_file_name);
+
+    let content = backend.get(&path_to_remote).await?;
+    let (_, content) = open(key, &remote_file_name, content)?;
+    let content = String::from_utf8(content)?;
+    parse_remote_week_page(file_name, &content)
+}
+
+// ローカルとリモートのファイルを比べて、同期が必要なファイルを取得する。
+// 同期が必要なファイルのリビジョンは同期し終えたときに記録するので、一覧での情報も返す
+async fn sync_file_map(
+    storage: &dyn Storage,
+    backend: &dyn SyncBackend,
+    key: Option<&crypto::Key>,
+    sync_state: &mut SyncState,
+    area: Area,
+    dir_on_remote: &str,
+    edited_files: &HashSet<String>,
+) -> Result<(HashMap<String, FileState>, HashMap<String, RemoteFile>)> {
+    let files_on_remote = list_remote(backend, sync_state, dir_on_remote).await?;
+
+    let local_files: HashSet<String> = match area {
+        Area::Pages => week_file_names(storage).await?,
+        _ => storage.list(area).await?,
+    }
+    .into_iter()
+    .collect();
+    // 暗号化されたゴミ箱の画像や名前を変えた画像もファイル名がわかるようにして、
+    // ダウンロードする前に除外できるようにする
+    let files_by_remote = match area {
+        Area::Images => {
+            let mut file_names = local_files.clone();
+            file_names.extend(storage.list(Area::TrashImages).await?);
+            file_names.extend(sync_state.renamed_images.iter().cloned());
+            local_file_names_by_remote(key, &file_names)
+        }
+        _ => local_file_names_by_remote(key, &local_files),
+    };
+    let mut changed_files = changed_on_remote(
+        sync_state,
+        dir_on_remote,
+        &files_on_remote,
+        &files_by_remote,
+    );
+    // 暗号化されたファイルは内容を比較できない。画像はローカルの方を優先するので比較しない
+    if area == Area::Pages && key.is_none() {
+        compare_content_hashes(
+            storage,
+            backend,
+            &files_on_remote,
+            &local_files,
+            &mut changed_files,
+        )
+        .await?;
+    }
+
+    let mut file_map = get_file_map(
+        &local_files,
+        edited_files,
+        &changed_files,
+        files_on_remote.iter().map(|f| {
+            files_by_remote
+                .get(&f.name)
+                .map(|name| name.as_ref())
+                .unwrap_or_else(|| f.name.as_ref())
+        }),
     )?;
+    if area == Area::Images {
+        file_map.retain(|file_name, state| {
+            state.exists_on_local || !sync_state.renamed_images.contains(file_name)
+        });
+    }
 
-    for (file_name, state) in file_map {
-        let path_to_remote = format!("{}/{}", PAGES_DIR_ON_DROPBOX, file_name);
-        let path_to_local = page_dir.join(&file_name);
+    let mut listed_files = HashMap::new();
+    for f in files_on_remote {
+        let file_name = files_by_remote
+            .get(&f.name)
+            .cloned()
+            .unwrap_or_else(|| f.name.clone());
+        if file_map.contains_key(&file_name) {
+            listed_files.insert(file_name, f);
+        } else {
+            record_revisions(sync_state, dir_on_remote, &[f]);
+        }
+    }
 
-        match (
-            state.exists_on_local,
-            state.exists_on_remote,
-            state.is_edited,
-        ) {
-            // ダウンロード
-            (false, true, false) => {
-                println!("{}をダウンロードしています...", file_name);
+    Ok((file_map, listed_files))
+}
+
+// 暗号化する場合は別のフォルダに同期する
+fn dirs_on_remote(encrypt: bool) -> (&'static str, &'static str) {
+    if encrypt {
+        (ENCRYPTED_PAGES_DIR_ON_REMOTE, ENCRYPTED_IMAGE_DIR_ON_REMOTE)
+    } else {
+        (PAGES_DIR_ON_REMOTE, IMAGE_DIR_ON_REMOTE)
+    }
+}
+
+// 同期の間変わらないもの
+struct SyncTarget<'a> {
+    storage: &'a dyn Storage,
+    backend: &'a dyn SyncBackend,
+    key: Option<&'a crypto::Key>,
+    pages_dir_on_remote: &'a str,
+    image_dir_on_remote: &'a str,
+    progress: &'a ProgressBar,
+}
 
-                let (_, content) =
-                    dropbox::download_file(client, access_token, &path_to_remote).await?;
+// 1つのファイルを同期した結果
+struct Transferred {
+    // ローカルでのファイル名
+    file_name: String,
+    // アップロードした場合は書き込んだファイルの情報
+    uploaded: Option<RemoteFile>,
+    bytes: usize,
+    // 新しい形式で保存されていたため同期しなかった。次回も同期する
+    skipped: bool,
+}
+
+impl Transferred {
+    fn new(file_name: String, uploaded: Option<RemoteFile>, bytes: usize) -> Self {
+        Self {
+            file_name,
+            uploaded,
+            bytes,
+            skipped: false,
+        }
+    }
+
+    fn skipped(file_name: String) -> Self {
+        Self {
+            file_name,
+            uploaded: None,
+            bytes: 0,
+            skipped: true,
+        }
+    }
+}
+
+async fn sync_page_file(
+    target: &SyncTarget<'_>,
+    file_name: String,
+    state: FileState,
+) -> Result<Transferred> {
+    let SyncTarget {
+        storage,
+        backend,
+        key,
+        pages_dir_on_remote,
+        progress,
+        ..
+    } = *target;
+
+    let path_to_remote = format!(
+        "{}/{}",
+        pages_dir_on_remote,
+        remote_file_name(key, &file_name)
+    );
+
+    match (
+        state.exists_on_local,
+        state.exists_on_remote,
+        state.is_edited,
+    ) {
+        // ダウンロード。ローカルにないファイルはリモートのファイル名のままになっている
+        (false, true, false) => {
+            let path_to_remote = format!("{}/{}", pages_dir_on_remote, file_name);
+            let content = backend.get(&path_to_remote).await?;
+            let bytes = content.len();
+            let (file_name, content) = open(key, &file_name, content)?;
+
+            // 古い形式のファイルは現在の形式に変換してから保存する
+            let content = String::from_utf8(content)?;
+            let wpage = match parse_remote_week_page(&file_name, &content)? {
+                Some(wpage) => wpage,
+                None => return Ok(Transferred::skipped(file_name)),
+            };
+
+            let json = serde_json::to_string(&wpage)?;
+            storage
+                .write(Area::Pages, &file_name, json.as_bytes())
+                .await?;
+            storage
+                .write(Area::SyncBase, &file_name, json.as_bytes())
+                .await?;
+
+            progress.println(format!("{}をダウンロードしました", file_name));
+
+            Ok(Transferred::new(file_name, None, bytes))
+        }
+        // アップロード
+        (true, false, true) => {
+            // アップロード日時を更新してからJSONに変換
+            let mut wpage = read_week_page(storage, &file_name).await?;
+            wpage.uploaded_at = Some(Utc::now());
+
+            let json = serde_json::to_string(&wpage)?;
+
+            // 内容のハッシュが一致するように、ローカルのファイルも更新する
+            storage
+                .write(Area::Pages, &file_name, json.as_bytes())
+                .await?;
 
-                fs::write(&path_to_local, content).await?;
-            }
             // アップロード
-            (true, false, true) => {
-                println!("{}をアップロードしています...", file_name);
+            let content = seal(key, &file_name, json.clone().into_bytes())?;
+            let bytes = content.len();
+            let uploaded = backend.put(&path_to_remote, content).await?;
+            storage
+                .write(Area::SyncBase, &file_name, json.as_bytes())
+                .await?;
+
+            progress.println(format!("{}をアップロードしました", file_name));
+
+            Ok(Transferred::new(file_name, Some(uploaded), bytes))
+        }
+        // 統合して双方を更新
+        (true, true, true) => {
+            // ローカルのページを読み込む
+            let wpage_on_local = read_week_page(storage, &file_name).await?;
+
+            // リモートのページを読み込む。新しい形式で保存されていたら上書きしない
+            let wpage_on_remote =
+                match read_remote_week_page(backend, key, pages_dir_on_remote, &file_name).await? {
+                    Some(wpage) => wpage,
+                    None => return Ok(Transferred::skipped(file_name)),
+                };
+
+            // 統合して、アップロード日時を更新
+            let base = read_sync_base(storage, &file_name).await?;
+            let (mut wpage, conflicts) = merge(wpage_on_local, wpage_on_remote, base.as_ref());
+            wpage.uploaded_at = Some(Utc::now());
+            for id in conflicts {
+                progress.println(format!(
+                    "ページ{}は両方の端末で編集されていたため、古い方をコピーとして残しました",
+                    id
+                ));
+            }
 
-                // アップロード日時を更新してからJSONに変換
-                let json = fs::read_to_string(&path_to_local).await?;
-                let mut wpage: WeekPage = serde_json::from_str(&json)?;
-                wpage.uploaded_at = Some(Utc::now());
+            let json = serde_json::to_string(&wpage)?;
 
-                let json = serde_json::to_string(&wpage)?;
+            // ローカルのファイルを更新
+            storage
+                .write(Area::Pages, &file_name, json.as_bytes())
+                .await?;
 
-                // アップロード
-                dropbox::upload_file(client, access_token, &path_to_remote, json).await?;
+            // リモートのファイルを更新
+            let content = seal(key, &file_name, json.clone().into_bytes())?;
+            let bytes = content.len();
+            let uploaded = backend.put(&path_to_remote, content).await?;
+            storage
+                .write(Area::SyncBase, &file_name, json.as_bytes())
+                .await?;
+
+            progress.println(format!("{}を更新しました", file_name));
+
+            Ok(Transferred::new(file_name, Some(uploaded), bytes))
+        }
+        (a, b, c) => unreachable!("({}, {}, {})", a, b, c),
+    }
+}
+
+async fn sync_image_file(
+    target: &SyncTarget<'_>,
+    file_name: String,
+    state: FileState,
+) -> Result<Transferred> {
+    let SyncTarget {
+        storage,
+        backend,
+        key,
+        image_dir_on_remote,
+        progress,
+        ..
+    } = *target;
+
+    let path_to_remote = format!(
+        "{}/{}",
+        image_dir_on_remote,
+        remote_file_name(key, &file_name)
+    );
+
+    match (
+        state.exists_on_local,
+        state.exists_on_remote,
+        state.is_edited,
+    ) {
+        // ゴミ箱にある画像はダウンロードしない
+        (false, true, false) if storage.exists(Area::TrashImages, &file_name).await? => {
+            Ok(Transferred::new(file_name, None, 0))
+        }
+        // 暗号化しない場合は大きな画像をメモリに溜めないように一時ファイルに書き込む
+        (false, true, false) if key.is_none() => {
+            let path_to_remote = format!("{}/{}", image_dir_on_remote, file_name);
+            let temp_path = env::temp_dir().join(Uuid::new_v4().to_string());
+
+            let written: Result<usize> = async {
+                backend.get_to_file(&path_to_remote, &temp_path).await?;
+                let bytes = fs::metadata(&temp_path).await?.len() as usize;
+                storage
+                    .write_from_file(Area::Images, &file_name, &temp_path)
+                    .await?;
+                Ok(bytes)
             }
-            // 統合して双方を更新
-            (true, true, true) => {
-                println!("{}を更新しています...", file_name);
+            .await;
+            let bytes = match written {
+                Ok(bytes) => bytes,
+                Err(err) => {
+                    let _ = fs::remove_file(&temp_path).await;
+                    return Err(err);
+                }
+            };
+
+            progress.println(format!("{}をダウンロードしました", file_name));
+
+            Ok(Transferred::new(file_name, None, bytes))
+        }
+        // ダウンロード。ローカルにないファイルはリモートのファイル名のままになっている
+        (false, true, false) => {
+            let path_to_remote = format!("{}/{}", image_dir_on_remote, file_name);
+            let content = backend.get(&path_to_remote).await?;
+            let bytes = content.len();
+            let (file_name, content) = open(key, &file_name, content)?;
+
+            // 暗号化されている場合は復号するまでファイル名がわからない
+            if storage.exists(Area::TrashImages, &file_name).await? {
+                return Ok(Transferred::new(file_name, None, bytes));
+            }
+
+            storage.write(Area::Images, &file_name, &content).await?;
+
+            progress.println(format!("{}をダウンロードしました", file_name));
+
+            Ok(Transferred::new(file_name, None, bytes))
+        }
+        // ローカルの画像を優先する。
+        // 選択できるようしてもよいかもしれない
+        (true, true, true) |
+        // アップロード
+        (true, false, true) => {
+            let image = storage.read(Area::Images, &file_name).await?;
+            let content = seal(key, &file_name, image)?;
+            let bytes = content.len();
+            let uploaded = backend.put(&path_to_remote, content).await?;
+
+            progress.println(format!("{}をアップロードしました", file_name));
+
+            Ok(Transferred::new(file_name, Some(uploaded), bytes))
+        }
+        (a, b, c) => unreachable!("({}, {}, {})", a, b, c),
+    }
+}
+
+// 同期し終えたファイルを記録する。途中で失敗しても、次回は残りのファイルから同期する
+async fn checkpoint(
+    storage: &dyn Storage,
+    sync_state: &mut SyncState,
+    area: Area,
+    dir_on_remote: &str,
+    listed_file: Option<RemoteFile>,
+    transferred: &Transferred,
+) -> Result<()> {
+    if transferred.skipped {
+        return Ok(());
+    }
 
-                // ローカルのページを読み込む
-                let json = fs::read_to_string(&path_to_local).await?;
-                let wpage_on_local: WeekPage = serde_json::from_str(&json)?;
+    // アップロードした場合は書き込んだファイルのリビジョンを記録する
+    if let Some(f) = transferred.uploaded.clone().or(listed_file) {
+        record_revisions(sync_state, dir_on_remote, &[f]);
+    }
+    storage.write_sync_state(sync_state).await?;
 
-                // リモートのページを読み込む
-                let (_, content) =
-                    dropbox::download_file_to_string(client, access_token, &path_to_remote).await?;
-                let wpage_on_remote = serde_json::from_str(&content)?;
+    update_edited_entries(storage, |entries| {
+        match area {
+            Area::Pages => entries.page_files.remove(&transferred.file_name),
+            _ => entries.image_files.remove(&transferred.file_name),
+        };
+    })
+    .await?;
 
-                // 統合して、アップロード日時を更新
-                let mut wpage = integrate(wpage_on_local, wpage_on_remote);
-                wpage.uploaded_at = Some(Utc::now());
+    Ok(())
+}
 
-                let json = serde_json::to_string(&wpage)?;
+// 同期するファイルの種類ごとの情報
+struct SyncArea<'a> {
+    area: Area,
+    dir_on_remote: &'a str,
+    file_map: HashMap<String, FileState>,
+    listed_files: HashMap<String, RemoteFile>,
+}
 
-                // ローカルのファイルを更新
-                fs::write(&path_to_local, &json).await?;
+// ファイルを並行して同期する。
+// 失敗したファイルがあれば新しく同期を始めるのをやめて、同期中のファイルが終わるのを待つ
+async fn sync_files(
+    target: &SyncTarget<'_>,
+    sync_state: &mut SyncState,
+    sync_area: SyncArea<'_>,
+    transferred_bytes: &mut usize,
+) -> Result<()> {
+    let SyncArea {
+        area,
+        dir_on_remote,
+        file_map,
+        mut listed_files,
+    } = sync_area;
+
+    let failed = AtomicBool::new(false);
+    let failed = &failed;
+
+    let mut transfers = stream::iter(file_map)
+        .map(|(file_name, state)| async move {
+            if failed.load(Ordering::SeqCst) {
+                return (file_name, None);
+            }
 
-                // リモートのファイルを更新
-                dropbox::upload_file(client, access_token, &path_to_remote, json).await?;
+            let result = match area {
+                Area::Pages => sync_page_file(target, file_name.clone(), state).await,
+                _ => sync_image_file(target, file_name.clone(), state).await,
+            };
+            (file_name, Some(result))
+        })
+        .buffer_unordered(MAX_CONCURRENT_TRANSFERS);
+
+    let mut first_error = None;
+    while let Some((file_name, result)) = transfers.next().await {
+        match result {
+            Some(Ok(transferred)) => {
+                let listed_file = listed_files.remove(&file_name);
+                checkpoint(
+                    target.storage,
+                    sync_state,
+                    area,
+                    dir_on_remote,
+                    listed_file,
+                    &transferred,
+                )
+                .await?;
+
+                *transferred_bytes += transferred.bytes;
+                target.progress.inc(1);
+                target
+                    .progress
+                    .set_message(&HumanBytes(*transferred_bytes as u64).to_string());
             }
-            (a, b, c) => unreachable!("({}, {}, {})", a, b, c),
+            Some(Err(err)) => {
+                target
+                    .progress
+                    .println(format!("{}を同期できませんでした: {}", file_name, err));
+                failed.store(true, Ordering::SeqCst);
+                first_error.get_or_insert(err);
+            }
+            None => {}
         }
     }
 
-    // 画像ファイルを同期
+    match first_error {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
 
-    let image_files_on_remote =
-        dropbox::list_files(client, access_token, IMAGE_DIR_ON_DROPBOX).await?;
+// 同期し終えたファイルは記録していくので、途中で失敗しても次回は続きから同期する
+pub async fn sync(storage: &dyn Storage, backend: &dyn SyncBackend, encrypt: bool) -> Result<()> {
+    let (pages_dir_on_remote, image_dir_on_remote) = dirs_on_remote(encrypt);
 
-    let image_dir = directory.join(IMAGE_DIR);
-    let file_map = get_file_map(
-        &image_dir,
+    let key = if encrypt {
+        Some(prepare_sync_key(storage, backend).await?)
+    } else {
+        None
+    };
+    let key = key.as_ref();
+
+    let edited_entries = storage.edited_entries().await?;
+    let mut sync_state = storage.sync_state().await?;
+
+    let (page_file_map, listed_page_files) = sync_file_map(
+        storage,
+        backend,
+        key,
+        &mut sync_state,
+        Area::Pages,
+        pages_dir_on_remote,
+        &edited_entries.page_files,
+    )
+    .await?;
+    let (image_file_map, listed_image_files) = sync_file_map(
+        storage,
+        backend,
+        key,
+        &mut sync_state,
+        Area::Images,
+        image_dir_on_remote,
         &edited_entries.image_files,
-        image_files_on_remote.iter().map(|f| f.name.as_ref()),
-    )?;
+    )
+    .await?;
 
-    for (file_name, state) in file_map {
-        let path_to_remote = format!("{}/{}", IMAGE_DIR_ON_DROPBOX, file_name);
-        let path_to_local = image_dir.join(&file_name);
+    let progress = ProgressBar::new((page_file_map.len() + image_file_map.len()) as u64);
+    progress.set_style(
+        ProgressStyle::default_bar()
+            .template("[{bar:30}] {pos}/{len} {msg}")
+            .progress_chars("=> "),
+    );
+
+    let target = SyncTarget {
+        storage,
+        backend,
+        key,
+        pages_dir_on_remote,
+        image_dir_on_remote,
+        progress: &progress,
+    };
+    let mut transferred_bytes = 0;
+
+    // ページファイルを同期
+    let pages = SyncArea {
+        area: Area::Pages,
+        dir_on_remote: pages_dir_on_remote,
+        file_map: page_file_map,
+        listed_files: listed_page_files,
+    };
+    let result = sync_files(&target, &mut sync_state, pages, &mut transferred_bytes).await;
+
+    // 画像ファイルを同期
+    let result = match result {
+        Ok(()) => {
+            let images = SyncArea {
+                area: Area::Images,
+                dir_on_remote: image_dir_on_remote,
+                file_map: image_file_map,
+                listed_files: listed_image_files,
+            };
+            sync_files(&target, &mut sync_state, images, &mut transferred_bytes).await
+        }
+        Err(err) => Err(err),
+    };
+
+    progress.finish_and_clear();
+    result?;
+
+    // 変更がなかったファイルのリビジョンとカーソルを記録する
+    storage.write_sync_state(&sync_state).await?;
+
+    Ok(())
+}
+
+// 同期で行うこと
+#[derive(Debug, PartialEq)]
+pub enum SyncAction {
+    Download,
+    Upload,
+    // 統合して双方を更新する。それぞれに追加されるページと、両方で編集されたページのID
+    Merge {
+        added_to_local: Vec<String>,
+        added_to_remote: Vec<String>,
+        conflicts: Vec<String>,
+    },
+    // 新しい形式で保存されているか、ゴミ箱にあるため同期しない
+    Skip,
+}
+
+#[derive(Debug)]
+pub struct SyncPlan {
+    // ファイル名の順に並べる
+    pub pages: Vec<(String, SyncAction)>,
+    pub images: Vec<(String, SyncAction)>,
+}
+
+// 統合したときにそれぞれに追加されるページを調べる
+async fn plan_merge(
+    storage: &dyn Storage,
+    backend: &dyn SyncBackend,
+    key: Option<&crypto::Key>,
+    pages_dir_on_remote: &str,
+    file_name: &str,
+) -> Result<SyncAction> {
+    let wpage_on_local = read_week_page(storage, file_name).await?;
+    let wpage_on_remote =
+        match read_remote_week_page(backend, key, pages_dir_on_remote, file_name).await? {
+            Some(wpage) => wpage,
+            None => return Ok(SyncAction::Skip),
+        };
+    let base = read_sync_base(storage, file_name).await?;
+
+    let ids = |wpage: &WeekPage| -> HashSet<String> {
+        wpage.pages.iter().map(|page| page.id.clone()).collect()
+    };
+    let local_ids = ids(&wpage_on_local);
+    let remote_ids = ids(&wpage_on_remote);
 
-        match (
+    let (wpage, conflicts) = merge(wpage_on_local, wpage_on_remote, base.as_ref());
+    let added = |ids: &HashSet<String>| -> Vec<String> {
+        wpage
+            .pages
+            .iter()
+            .filter(|page| !ids.contains(&page.id))
+            .map(|page| page.id.clone())
+            .collect()
+    };
+
+    Ok(SyncAction::Merge {
+        added_to_local: added(&local_ids),
+        added_to_remote: added(&remote_ids),
+        conflicts,
+    })
+}
+
+// ローカルにもリモートにも書き込まずに、同期したときに行うことを調べる。
+// 暗号化されたリモートにしかないファイルは、リモートでのファイル名になる
+pub async fn plan_sync(
+    storage: &dyn Storage,
+    backend: &dyn SyncBackend,
+    encrypt: bool,
+) -> Result<SyncPlan> {
+    let (pages_dir_on_remote, image_dir_on_remote) = dirs_on_remote(encrypt);
+
+    let key = if encrypt {
+        unlock_sync_key(backend).await?
+    } else {
+        None
+    };
+    let key = key.as_ref();
+
+    let mut edited_entries = storage.edited_entries().await?;
+    // 暗号化した同期先がまだなければ、すべてのファイルをアップロードすることになる
+    if encrypt && key.is_none() {
+        edited_entries
+            .page_files
+            .extend(week_file_names(storage).await?);
+        edited_entries
+            .image_files
+            .extend(storage.list(Area::Images).await?);
+    }
+
+    // 一覧を取得すると更新されるが、保存はしない
+    let mut sync_state = storage.sync_state().await?;
+
+    let mut plan = SyncPlan {
+        pages: Vec::new(),
+        images: Vec::new(),
+    };
+
+    let (file_map, _) = sync_file_map(
+        storage,
+        backend,
+        key,
+        &mut sync_state,
+        Area::Pages,
+        pages_dir_on_remote,
+        &edited_entries.page_files,
+    )
+    .await?;
+
+    for (file_name, state) in file_map {
+        let action = match (
             state.exists_on_local,
             state.exists_on_remote,
             state.is_edited,
         ) {
-            // ダウンロード
-            (false, true, false) => {
-                println!("{}をダウンロードしています...", file_name);
-
-                let (_, content) = dropbox::download_file(client, access_token, &path_to_remote).await?;
-                fs::write(&path_to_local, content).await?;
+            (false, true, false) => SyncAction::Download,
+            (true, false, true) => SyncAction::Upload,
+            (true, true, true) => {
+                plan_merge(storage, backend, key, pages_dir_on_remote, &file_name).await?
             }
-            // ローカルの画像を優先する。
-            // 選択できるようしてもよいかもしれない
-            (true, true, true) |
-            // アップロード
-            (true, false, true) => {
-                println!("{}をアップロードしています...", file_name);
+            (a, b, c) => unreachable!("({}, {}, {})", a, b, c),
+        };
+        plan.pages.push((file_name, action));
+    }
 
-                let image = fs::read(&path_to_local).await?;
-                dropbox::upload_file(client, access_token, &path_to_remote, image).await?;
-            },
+    let (file_map, _) = sync_file_map(
+        storage,
+        backend,
+        key,
+        &mut sync_state,
+        Area::Images,
+        image_dir_on_remote,
+        &edited_entries.image_files,
+    )
+    .await?;
+
+    for (file_name, state) in file_map {
+        let action = match (
+            state.exists_on_local,
+            state.exists_on_remote,
+            state.is_edited,
+        ) {
+            (false, true, false) if storage.exists(Area::TrashImages, &file_name).await? => {
+                SyncAction::Skip
+            }
+            (false, true, false) => SyncAction::Download,
+            // ローカルの画像を優先する
+            (true, true, true) | (true, false, true) => SyncAction::Upload,
             (a, b, c) => unreachable!("({}, {}, {})", a, b, c),
-        }
+        };
+        plan.images.push((file_name, action));
     }
 
-    // 更新済みリストを空にする
-    update_edited_entries(directory, EditedEntries::clear).await?;
+    plan.pages.sort_by(|a, b| a.0.cmp(&b.0));
+    plan.images.sort_by(|a, b| a.0.cmp(&b.0));
 
-    Ok(())
+    Ok(plan)
 }
 
 // ==============================
-// バックアップ
+// ゴミ箱
 // ==============================
 
-fn generate_backup_dir_path(base: &Path, prefix: &str, id: u32) -> PathBuf {
-    base.join(&format!("{}_{}", prefix, id))
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrashedPage {
+    pub page: Page,
+    pub deleted_at: DateTime<Utc>,
 }
 
-fn generate_backup_dir_path_not_exists(base: &Path, prefix: &str) -> (PathBuf, u32) {
-    let mut id = 1;
-    loop {
-        let path = generate_backup_dir_path(base, prefix, id);
-        if !path.exists() {
-            return (path, id);
+fn generate_trash_file_name(id: &str) -> String {
+    format!("{}.json", id)
+}
+
+async fn move_to_trash(
+    storage: &dyn Storage,
+    page: &Page,
+    deleted_at: DateTime<Utc>,
+) -> Result<()> {
+    // ページで使われている画像を移動する
+    let image_files = page::image_file_names(&page.text);
+    for file_name in &image_files {
+        if storage.exists(Area::Images, file_name).await? {
+            storage
+                .rename(Area::Images, file_name, Area::TrashImages, file_name)
+                .await?;
+        }
+    }
+
+    // 移動した画像はアップロードしない
+    update_edited_entries(storage, |entries| {
+        for file_name in &image_files {
+            entries.image_files.remove(file_name);
         }
+    })
+    .await?;
+
+    let trashed_page = TrashedPage {
+        page: page.clone(),
+        deleted_at,
+    };
+    let json = serde_json::to_string(&trashed_page)?;
+    storage
+        .write(
+            Area::TrashPages,
+            &generate_trash_file_name(&page.id),
+            json.as_bytes(),
+        )
+        .await?;
 
-        id += 1;
+    Ok(())
+}
+
+// ゴミ箱のページのファイル名を取得する
+pub async fn trash_file_names(storage: &dyn Storage) -> Result<Vec<String>> {
+    Ok(storage
+        .list(Area::TrashPages)
+        .await?
+        .into_iter()
+        .filter(|file_name| file_name.ends_with(".json"))
+        .collect())
+}
+
+// ゴミ箱のページを削除された日時の降順で取得する
+pub async fn list_trash(storage: &dyn Storage) -> Result<Vec<TrashedPage>> {
+    let mut trashed_pages = Vec::new();
+
+    for file_name in trash_file_names(storage).await? {
+        let json = read_to_string(storage, Area::TrashPages, &file_name).await?;
+        let trashed_page: TrashedPage = serde_json::from_str(&json)?;
+        trashed_pages.push(trashed_page);
     }
+
+    trashed_pages.sort_by_key(|trashed_page| Reverse(trashed_page.deleted_at));
+
+    Ok(trashed_pages)
 }
 
-pub async fn create_pages_backup(directory: &Path) -> Result<u32> {
-    let page_dir = directory.join(PAGE_DIR);
-    let (backup_dir, id) = generate_backup_dir_path_not_exists(directory, BACKUP_DIR_PREFIX);
+// 本文で使われている画像がゴミ箱にあれば戻す。どこにもなかった画像のファイル名を返す
+pub async fn restore_images(storage: &dyn Storage, text: &str) -> Result<Vec<String>> {
+    let mut missing = Vec::new();
 
-    // PAGE_DIR内のすべてのファイルをコピーする
-    fs::create_dir(&backup_dir).await?;
+    for file_name in page::image_file_names(text) {
+        if storage.exists(Area::Images, &file_name).await? {
+            continue;
+        }
 
-    let mut entries = fs::read_dir(&page_dir).await?;
-    while let Some(entry) = entries.next().await {
-        let entry = entry?;
-        if let Ok(ft) = entry.file_type().await {
-            if ft.is_file() {
-                let to = backup_dir.join(entry.path().as_path().file_name().unwrap());
-                fs::copy(entry.path(), &to).await?;
-            }
+        if storage.exists(Area::TrashImages, &file_name).await? {
+            storage
+                .rename(Area::TrashImages, &file_name, Area::Images, &file_name)
+                .await?;
+
+            update_edited_entries(storage, |entries| {
+                entries.image_files.insert(file_name);
+            })
+            .await?;
+        } else {
+            missing.push(file_name);
         }
     }
 
-    Ok(id)
+    Ok(missing)
 }
 
-pub async fn rollback(directory: &Path, id: u32) -> Result<()> {
-    let page_dir = directory.join(PAGE_DIR);
-    let backup_dir = generate_backup_dir_path(directory, BACKUP_DIR_PREFIX, id);
+// ゴミ箱のページを元の週のファイルに戻す
+pub async fn restore_from_trash(storage: &dyn Storage, id: &str) -> Result<Page> {
+    let trash_file_name = generate_trash_file_name(id);
+    let json = read_to_string(storage, Area::TrashPages, &trash_file_name).await?;
+    let trashed_page: TrashedPage = serde_json::from_str(&json)?;
+    let mut page = trashed_page.page;
+
+    // 画像を戻す
+    restore_images(storage, &page.text).await?;
+
+    // 削除記録より新しくしておかないと同期したときに再び削除される
+    page.updated_at.push(Utc::now());
+
+    write(storage, page.clone()).await?;
+    storage.remove(Area::TrashPages, &trash_file_name).await?;
+
+    Ok(page)
+}
 
-    fs::remove_dir_all(&page_dir).await?;
-    fs::create_dir(&page_dir).await?;
+// beforeより前に削除されたページをゴミ箱から完全に削除して、削除した数を返す
+pub async fn empty_trash(storage: &dyn Storage, before: DateTime<Utc>) -> Result<usize> {
+    let mut count = 0;
 
-    // backup_dir内のすべてのファイルをコピーする
-    let mut entries = fs::read_dir(&backup_dir).await?;
-    while let Some(entry) = entries.next().await {
-        let entry = entry?;
-        if let Ok(ft) = entry.file_type().await {
-            if ft.is_file() {
-                let to = page_dir.join(entry.path().as_path().file_name().unwrap());
-                fs::copy(entry.path(), &to).await?;
+    for trashed_page in list_trash(storage).await? {
+        if trashed_page.deleted_at >= before {
+            continue;
+        }
+
+        for file_name in page::image_file_names(&trashed_page.page.text) {
+            if storage.exists(Area::TrashImages, &file_name).await? {
+                storage.remove(Area::TrashImages, &file_name).await?;
             }
         }
-    }
 
-    remove_pages_backup(directory, id).await?;
+        storage
+            .remove(
+                Area::TrashPages,
+                &generate_trash_file_name(&trashed_page.page.id),
+            )
+            .await?;
+        count += 1;
+    }
 
-    Ok(())
+    Ok(count)
 }
 
-pub async fn remove_pages_backup(directory: &Path, id: u32) -> Result<()> {
-    let backup_dir = generate_backup_dir_path(directory, BACKUP_DIR_PREFIX, id);
-    fs::remove_dir_all(backup_dir).await?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{Changes, LocalBackend};
+    use crate::dropbox;
+    use crate::testing::{page, TempDir};
+    use chrono::TimeZone;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_integrate_with_tombstones() {
+        let created_at = Utc.ymd(2020, 4, 1).and_hms(10, 0, 0);
+
+        // ローカルで"a"を削除
+        let mut local = WeekPage::new();
+        local.pages.push(page("b", created_at));
+        local.deleted.push(Tombstone {
+            id: String::from("a"),
+            deleted_at: Utc.ymd(2020, 4, 2).and_hms(0, 0, 0),
+        });
 
-    Ok(())
-}
+        let mut remote = WeekPage::new();
+        remote.pages.push(page("a", created_at));
+        remote.pages.push(page("b", created_at));
+        remote.pages.push(page("c", created_at));
+
+        let wpage = integrate(local, remote);
+        let ids: Vec<&str> = wpage.pages.iter().map(|page| page.id.as_ref()).collect();
+        assert_eq!(vec!["b", "c"], ids);
+        assert_eq!(1, wpage.deleted.len());
+
+        // 削除記録は統合後も残り、リモートからページが戻ってこない
+        let mut remote = WeekPage::new();
+        remote.pages.push(page("a", created_at));
+        let wpage = integrate(remote, wpage);
+        let ids: Vec<&str> = wpage.pages.iter().map(|page| page.id.as_ref()).collect();
+        assert_eq!(vec!["b", "c"], ids);
+    }
 
-// ==============================
-// 修正
-// ==============================
+    fn ids(pages: &[Page]) -> Vec<&str> {
+        pages.iter().map(|page| page.id.as_ref()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_write_and_list() {
+        let storage = MemoryStorage::new();
+
+        // 同じ週に2つ、別の週に1つ
+        write(&storage, page("a", Utc.ymd(2020, 3, 30).and_hms(10, 0, 0)))
+            .await
+            .unwrap();
+        write(&storage, page("b", Utc.ymd(2020, 4, 1).and_hms(10, 0, 0)))
+            .await
+            .unwrap();
+        write(&storage, page("c", Utc.ymd(2020, 4, 6).and_hms(10, 0, 0)))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            vec!["2020-04-05-2020-04-11.json", "2020-03-29-2020-04-04.json"],
+            week_file_names(&storage).await.unwrap()
+        );
+
+        // 新しい順に取得する
+        let pages = list(&storage, 10).await.unwrap();
+        assert_eq!(vec!["c", "b", "a"], ids(&pages));
+
+        let pages = list_with_filter(&storage, 1, |page| page.id != "c")
+            .await
+            .unwrap();
+        assert_eq!(vec!["b"], ids(&pages));
+
+        // 同じIDのページは上書きする
+        let mut updated = page("b", Utc.ymd(2020, 4, 1).and_hms(10, 0, 0));
+        updated.title = String::from("updated");
+        write(&storage, updated).await.unwrap();
+        let pages = list(&storage, 10).await.unwrap();
+        assert_eq!(3, pages.len());
+        assert_eq!("updated", pages[1].title);
+
+        let entries = storage.edited_entries().await.unwrap();
+        assert_eq!(2, entries.page_files.len());
+    }
 
-fn convert_week_page_v1_to_v2(wpage: WeekPageV1) -> WeekPage {
-    WeekPage {
-        pages: wpage
+    #[tokio::test]
+    async fn test_delete_and_restore() {
+        let storage = MemoryStorage::new();
+
+        let mut page = page("a", Utc.ymd(2020, 4, 1).and_hms(10, 0, 0));
+        page.text = String::from("![](image.png)");
+        write(&storage, page).await.unwrap();
+        storage
+            .write(Area::Images, "image.png", b"image")
+            .await
+            .unwrap();
+
+        let deleted = delete(&storage, "a").await.unwrap().unwrap();
+        assert_eq!("a", deleted.id);
+        assert!(list(&storage, 10).await.unwrap().is_empty());
+        assert!(!storage.exists(Area::Images, "image.png").await.unwrap());
+        assert!(storage
+            .exists(Area::TrashImages, "image.png")
+            .await
+            .unwrap());
+        assert_eq!(1, list_trash(&storage).await.unwrap().len());
+
+        restore_from_trash(&storage, "a").await.unwrap();
+        assert_eq!(vec!["a"], ids(&list(&storage, 10).await.unwrap()));
+        assert!(storage.exists(Area::Images, "image.png").await.unwrap());
+        assert!(list_trash(&storage).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_retime() {
+        let dir = TempDir::new();
+        let backend = LocalBackend::new(dir.path());
+        let storage = MemoryStorage::new();
+
+        let created_at = Utc.ymd(2020, 4, 1).and_hms(10, 0, 0);
+        let image = format!("{}image.png", page::generate_image_prefix(&created_at));
+        let mut page = page("a", created_at);
+        page.text = format!("![]({})", image);
+        write(&storage, page).await.unwrap();
+        storage.write(Area::Images, &image, b"image").await.unwrap();
+        sync(&storage, &backend, false).await.unwrap();
+
+        // 別の週に移動する
+        let new_created_at = Utc.ymd(2020, 4, 8).and_hms(10, 0, 0);
+        let page = retime(&storage, "a", new_created_at).await.unwrap();
+        let new_image = format!("{}image.png", page::generate_image_prefix(&new_created_at));
+        assert_eq!(format!("![]({})", new_image), page.text);
+        assert!(storage.exists(Area::Images, &new_image).await.unwrap());
+        assert!(!storage.exists(Area::Images, &image).await.unwrap());
+
+        let pages = list(&storage, 10).await.unwrap();
+        assert_eq!(vec!["a"], ids(&pages));
+        assert_eq!(new_created_at, pages[0].created_at);
+
+        // 古い週には削除記録が残る
+        let old_week = read_week_page(&storage, "2020-03-29-2020-04-04.json")
+            .await
+            .unwrap();
+        assert!(old_week.pages.is_empty());
+        assert_eq!(1, old_week.deleted.len());
+
+        // 古い名前の画像はリモートに残っているが、ダウンロードし直さない
+        sync(&storage, &backend, false).await.unwrap();
+        assert!(storage.exists(Area::Images, &new_image).await.unwrap());
+        assert!(!storage.exists(Area::Images, &image).await.unwrap());
+
+        // 他の端末には新しい名前の画像が届く
+        let storage2 = MemoryStorage::new();
+        sync(&storage2, &backend, false).await.unwrap();
+        assert!(storage2.exists(Area::Images, &new_image).await.unwrap());
+    }
+
+    #[test]
+    fn test_merge() {
+        let t1 = Utc.ymd(2020, 4, 1).and_hms(10, 0, 0);
+        let edit = |page: &Page, text: &str, updated_at: DateTime<Utc>| {
+            let mut page = page.clone();
+            page.text = text.to_string();
+            page.updated_at.push(updated_at);
+            page
+        };
+
+        let mut base = WeekPage::new();
+        base.pages.push(page("a", t1));
+        base.pages.push(page("b", t1));
+        base.pages.push(page("c", t1));
+
+        // "a"はローカル、"b"はリモートで編集し、"c"は両方で編集する
+        let mut local = base.clone();
+        local.pages[0] = edit(&base.pages[0], "local", t1 + Duration::hours(1));
+        local.pages[2] = edit(&base.pages[2], "local", t1 + Duration::hours(1));
+        let mut remote = base.clone();
+        remote.pages[1] = edit(&base.pages[1], "remote", t1 + Duration::hours(2));
+        remote.pages[2] = edit(&base.pages[2], "remote", t1 + Duration::hours(2));
+
+        let (wpage, conflicts) = merge(local.clone(), remote.clone(), Some(&base));
+        let texts: Vec<(&str, &str)> = wpage
             .pages
-            .into_iter()
-            .map(|v1| Page {
-                id: Uuid::new_v4().to_string(),
-                title: v1.title,
-                text: v1.text,
-                hidden: v1.hidden,
-                created_at: v1.created_at,
-                updated_at: v1.updated_at,
-            })
-            .collect(),
-        uploaded_at: wpage.uploaded_at,
+            .iter()
+            .map(|page| (page.title.as_ref(), page.text.as_ref()))
+            .collect();
+        assert_eq!(
+            vec![
+                ("a", "local"),
+                ("b", "remote"),
+                ("c", "remote"),
+                ("c (競合したコピー)", "local")
+            ],
+            texts
+        );
+        assert_eq!(vec!["c"], conflicts);
+        assert_ne!("c", wpage.pages[3].id);
+
+        // 前回の内容がなくても更新日時の履歴で判断する
+        let (wpage, conflicts) = merge(local, remote, None);
+        assert_eq!(4, wpage.pages.len());
+        assert_eq!("local", wpage.pages[0].text);
+        assert_eq!(vec!["c"], conflicts);
     }
-}
 
-pub async fn fix_1_to_2(directory: &Path) -> Result<()> {
-    // ページが格納されているディレクトリのファイルをすべて取得する
-    let entries = directory
-        .join(PAGE_DIR)
-        .read_dir()?
-        .filter(|entry| entry.is_ok())
-        .map(|entry| entry.unwrap());
+    #[tokio::test]
+    async fn test_rollback() {
+        let storage = MemoryStorage::new();
+        write(&storage, page("a", Utc.ymd(2020, 4, 1).and_hms(10, 0, 0)))
+            .await
+            .unwrap();
+        write(&storage, page("b", Utc.ymd(2020, 4, 8).and_hms(10, 0, 0)))
+            .await
+            .unwrap();
+        delete(&storage, "b").await.unwrap();
+
+        let id = storage.create_pages_backup().await.unwrap();
+
+        // ページとゴミ箱の両方を書き換えてから元に戻す
+        write(&storage, page("c", Utc.ymd(2020, 4, 2).and_hms(10, 0, 0)))
+            .await
+            .unwrap();
+        empty_trash(&storage, Utc::now() + Duration::days(1))
+            .await
+            .unwrap();
+        storage.rollback(id).await.unwrap();
+
+        assert_eq!(vec!["a"], ids(&list(&storage, 10).await.unwrap()));
+        assert_eq!(1, list_trash(&storage).await.unwrap().len());
+        assert!(storage.remove_pages_backup(id).await.is_err());
+    }
 
-    for entry in entries {
-        let json = fs::read_to_string(entry.path()).await?;
-        let wpage: WeekPageV1 = serde_json::from_str(&json)?;
+    #[tokio::test]
+    async fn test_plan_sync() {
+        let dir = TempDir::new();
+        let backend = LocalBackend::new(dir.path());
+
+        let storage1 = MemoryStorage::new();
+        write(&storage1, page("a", Utc.ymd(2020, 4, 1).and_hms(10, 0, 0)))
+            .await
+            .unwrap();
+        storage1
+            .write(Area::Images, "image.png", b"image")
+            .await
+            .unwrap();
+        update_edited_entries(&storage1, |entries| {
+            entries.image_files.insert(String::from("image.png"));
+        })
+        .await
+        .unwrap();
+
+        let plan = plan_sync(&storage1, &backend, false).await.unwrap();
+        let file_name = String::from("2020-03-29-2020-04-04.json");
+        assert_eq!(vec![(file_name.clone(), SyncAction::Upload)], plan.pages);
+        assert_eq!(
+            vec![(String::from("image.png"), SyncAction::Upload)],
+            plan.images
+        );
+        // 何も書き込まない
+        assert!(backend.list("/pages").await.unwrap().is_empty());
+
+        sync(&storage1, &backend, false).await.unwrap();
+
+        // 同じ週に書いたページを統合する
+        let storage2 = MemoryStorage::new();
+        write(&storage2, page("b", Utc.ymd(2020, 4, 2).and_hms(10, 0, 0)))
+            .await
+            .unwrap();
+
+        let plan = plan_sync(&storage2, &backend, false).await.unwrap();
+        assert_eq!(
+            vec![(
+                file_name.clone(),
+                SyncAction::Merge {
+                    added_to_local: vec![String::from("a")],
+                    added_to_remote: vec![String::from("b")],
+                    conflicts: Vec::new(),
+                }
+            )],
+            plan.pages
+        );
+        assert_eq!(
+            vec![(String::from("image.png"), SyncAction::Download)],
+            plan.images
+        );
+        assert!(!storage2.exists(Area::Images, "image.png").await.unwrap());
+        assert_eq!(vec!["b"], ids(&list(&storage2, 10).await.unwrap()));
+    }
 
-        let wpage = convert_week_page_v1_to_v2(wpage);
-        let json = serde_json::to_string(&wpage)?;
-        fs::write(entry.path(), json).await?;
+    #[tokio::test]
+    async fn test_skip_trashed_images_when_encrypted() {
+        let dir = TempDir::new();
+        let backend = LocalBackend::new(dir.path());
+        std::env::set_var(crypto::SYNC_PASSPHRASE_ENV, "passphrase");
+
+        let storage1 = MemoryStorage::new();
+        storage1
+            .write(Area::Images, "image.png", b"image")
+            .await
+            .unwrap();
+        sync(&storage1, &backend, true).await.unwrap();
+
+        // ゴミ箱にある画像は暗号化されたファイル名でも見分けて、ダウンロードしない
+        let storage2 = MemoryStorage::new();
+        storage2
+            .write(Area::TrashImages, "image.png", b"image")
+            .await
+            .unwrap();
+        let plan = plan_sync(&storage2, &backend, true).await.unwrap();
+        assert_eq!(
+            vec![(String::from("image.png"), SyncAction::Skip)],
+            plan.images
+        );
+
+        sync(&storage2, &backend, true).await.unwrap();
+        assert!(!storage2.exists(Area::Images, "image.png").await.unwrap());
     }
 
-    Ok(())
+    #[tokio::test]
+    async fn test_sync_with_local_backend() {
+        let dir = TempDir::new();
+        let backend = LocalBackend::new(dir.path());
+
+        // 一方の端末で書いたページと画像をアップロードする
+        let storage1 = MemoryStorage::new();
+        write(&storage1, page("a", Utc.ymd(2020, 4, 1).and_hms(10, 0, 0)))
+            .await
+            .unwrap();
+        storage1
+            .write(Area::Images, "image.png", b"image")
+            .await
+            .unwrap();
+        update_edited_entries(&storage1, |entries| {
+            entries.image_files.insert(String::from("image.png"));
+        })
+        .await
+        .unwrap();
+        sync(&storage1, &backend, false).await.unwrap();
+
+        // もう一方の端末で書いたページと統合される
+        let storage2 = MemoryStorage::new();
+        write(&storage2, page("b", Utc.ymd(2020, 4, 2).and_hms(10, 0, 0)))
+            .await
+            .unwrap();
+        sync(&storage2, &backend, false).await.unwrap();
+
+        let pages = list(&storage2, 10).await.unwrap();
+        assert_eq!(vec!["b", "a"], ids(&pages));
+        assert_eq!(
+            b"image".to_vec(),
+            storage2.read(Area::Images, "image.png").await.unwrap()
+        );
+        assert!(storage2
+            .edited_entries()
+            .await
+            .unwrap()
+            .page_files
+            .is_empty());
+
+        // リモートで変更されたファイルはローカルで編集していなくても統合する
+        sync(&storage1, &backend, false).await.unwrap();
+        let pages = list(&storage1, 10).await.unwrap();
+        assert_eq!(vec!["b", "a"], ids(&pages));
+    }
+
+    #[tokio::test]
+    async fn test_sync_to_new_backend() {
+        let dir = TempDir::new();
+        let backend = LocalBackend::new(dir.path());
+
+        // 前の同期先と同期し終えて、編集したファイルの記録が消えている
+        let storage = MemoryStorage::new();
+        write(&storage, page("a", Utc.ymd(2020, 4, 1).and_hms(10, 0, 0)))
+            .await
+            .unwrap();
+        storage
+            .write(Area::Images, "image.png", b"image")
+            .await
+            .unwrap();
+        storage
+            .write_edited_entries(&EditedEntries::new())
+            .await
+            .unwrap();
+
+        // 空の同期先に切り替えても、すべてのファイルがアップロードされる
+        sync(&storage, &backend, false).await.unwrap();
+
+        let storage2 = MemoryStorage::new();
+        sync(&storage2, &backend, false).await.unwrap();
+        let pages = list(&storage2, 10).await.unwrap();
+        assert_eq!(vec!["a"], ids(&pages));
+        assert_eq!(
+            b"image".to_vec(),
+            storage2.read(Area::Images, "image.png").await.unwrap()
+        );
+    }
+
+    // 指定したファイルのアップロードだけが失敗する同期先
+    struct FailingBackend {
+        inner: LocalBackend,
+        failing_path: Mutex<Option<String>>,
+    }
+
+    #[async_trait]
+    impl SyncBackend for FailingBackend {
+        async fn list(&self, dir: &str) -> Result<Vec<RemoteFile>> {
+            self.inner.list(dir).await
+        }
+
+        async fn get(&self, path: &str) -> Result<Vec<u8>> {
+            self.inner.get(path).await
+        }
+
+        async fn put(&self, path: &str, contents: Vec<u8>) -> Result<RemoteFile> {
+            if self.failing_path.lock().unwrap().as_deref() == Some(path) {
+                return Err(anyhow!("接続が切れました"));
+            }
+            self.inner.put(path, contents).await
+        }
+
+        async fn delete(&self, path: &str) -> Result<()> {
+            self.inner.delete(path).await
+        }
+
+        async fn metadata(&self, path: &str) -> Result<Option<RemoteFile>> {
+            self.inner.metadata(path).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resume_sync() {
+        let dir = TempDir::new();
+        let backend = FailingBackend {
+            inner: LocalBackend::new(dir.path()),
+            failing_path: Mutex::new(Some(String::from("/images/b.png"))),
+        };
+
+        let storage = MemoryStorage::new();
+        write(&storage, page("a", Utc.ymd(2020, 4, 1).and_hms(10, 0, 0)))
+            .await
+            .unwrap();
+        storage
+            .write(Area::Images, "b.png", b"image")
+            .await
+            .unwrap();
+        update_edited_entries(&storage, |entries| {
+            entries.image_files.insert(String::from("b.png"));
+        })
+        .await
+        .unwrap();
+
+        // 失敗しても同期し終えたファイルは元に戻さない
+        assert!(sync(&storage, &backend, false).await.is_err());
+        let entries = storage.edited_entries().await.unwrap();
+        assert!(entries.page_files.is_empty());
+        assert_eq!(
+            vec!["b.png"],
+            entries.image_files.iter().collect::<Vec<_>>()
+        );
+        assert_eq!(1, backend.list("/pages").await.unwrap().len());
+        assert!(backend.list("/images").await.unwrap().is_empty());
+
+        // 次回は残りのファイルだけを同期する
+        *backend.failing_path.lock().unwrap() = None;
+        let plan = plan_sync(&storage, &backend, false).await.unwrap();
+        assert!(plan.pages.is_empty());
+        assert_eq!(
+            vec![(String::from("b.png"), SyncAction::Upload)],
+            plan.images
+        );
+
+        sync(&storage, &backend, false).await.unwrap();
+        assert!(storage
+            .edited_entries()
+            .await
+            .unwrap()
+            .image_files
+            .is_empty());
+        assert_eq!(
+            vec!["b.png"],
+            names(&backend.list("/images").await.unwrap())
+        );
+    }
+
+    fn remote_file(name: &str, revision: &str) -> RemoteFile {
+        RemoteFile {
+            name: name.to_string(),
+            modified: Utc.ymd(2020, 4, 5).and_hms(10, 0, 0),
+            revision: Some(revision.to_string()),
+            content_hash: None,
+        }
+    }
+
+    // カーソルで変更を取得できる同期先
+    struct CursorBackend {
+        changes: Mutex<Option<Changes>>,
+    }
+
+    #[async_trait]
+    impl SyncBackend for CursorBackend {
+        async fn list(&self, _dir: &str) -> Result<Vec<RemoteFile>> {
+            unreachable!()
+        }
+
+        async fn get(&self, _path: &str) -> Result<Vec<u8>> {
+            unreachable!()
+        }
+
+        async fn put(&self, _path: &str, _contents: Vec<u8>) -> Result<RemoteFile> {
+            unreachable!()
+        }
+
+        async fn delete(&self, _path: &str) -> Result<()> {
+            unreachable!()
+        }
+
+        async fn metadata(&self, _path: &str) -> Result<Option<RemoteFile>> {
+            unreachable!()
+        }
+
+        async fn list_with_cursor(&self, _dir: &str) -> Result<(Vec<RemoteFile>, Option<String>)> {
+            let files = vec![remote_file("a.json", "1"), remote_file("b.json", "1")];
+            Ok((files, Some(String::from("1"))))
+        }
+
+        async fn list_changes(&self, _dir: &str, _cursor: &str) -> Result<Option<Changes>> {
+            Ok(self.changes.lock().unwrap().take())
+        }
+
+        fn content_hash(&self, contents: &[u8]) -> Option<String> {
+            Some(dropbox::content_hash(contents))
+        }
+    }
+
+    fn names(files: &[RemoteFile]) -> Vec<&str> {
+        let mut names: Vec<&str> = files.iter().map(|f| f.name.as_ref()).collect();
+        names.sort();
+        names
+    }
+
+    #[tokio::test]
+    async fn test_list_remote_with_cursor() {
+        let backend = CursorBackend {
+            changes: Mutex::new(Some(Changes {
+                updated: vec![remote_file("a.json", "2"), remote_file("c.json", "1")],
+                deleted: vec![String::from("b.json")],
+                cursor: String::from("2"),
+            })),
+        };
+        let mut sync_state = SyncState::default();
+
+        let files = list_remote(&backend, &mut sync_state, "/pages")
+            .await
+            .unwrap();
+        assert_eq!(vec!["a.json", "b.json"], names(&files));
+        assert_eq!("1", sync_state.listings["/pages"].cursor);
+
+        // 前回の一覧に変更を反映する
+        let files = list_remote(&backend, &mut sync_state, "/pages")
+            .await
+            .unwrap();
+        assert_eq!(vec!["a.json", "c.json"], names(&files));
+        assert_eq!(
+            Some("2"),
+            sync_state.listings["/pages"].files["a.json"]
+                .revision
+                .as_deref()
+        );
+        assert_eq!("2", sync_state.listings["/pages"].cursor);
+
+        // カーソルが無効になったらすべて取得し直す
+        let files = list_remote(&backend, &mut sync_state, "/pages")
+            .await
+            .unwrap();
+        assert_eq!(vec!["a.json", "b.json"], names(&files));
+        assert_eq!("1", sync_state.listings["/pages"].cursor);
+    }
+
+    #[tokio::test]
+    async fn test_compare_content_hashes() {
+        let backend = CursorBackend {
+            changes: Mutex::new(None),
+        };
+
+        let storage = MemoryStorage::new();
+        storage.write(Area::Pages, "a.json", b"a").await.unwrap();
+        storage.write(Area::Pages, "b.json", b"b").await.unwrap();
+        let local_files: HashSet<String> = week_file_names(&storage)
+            .await
+            .unwrap()
+            .into_iter()
+            .collect();
+
+        // "a.json"はリビジョンが変わっているが内容は同じ。"b.json"は他の端末で編集された
+        let mut a = remote_file("a.json", "2");
+        a.content_hash = Some(dropbox::content_hash(b"a"));
+        let mut b = remote_file("b.json", "1");
+        b.content_hash = Some(dropbox::content_hash(b"edited"));
+        let mut changed_files: HashSet<String> = vec![String::from("a.json")].into_iter().collect();
+
+        compare_content_hashes(
+            &storage,
+            &backend,
+            &[a, b],
+            &local_files,
+            &mut changed_files,
+        )
+        .await
+        .unwrap();
+
+        let expected: HashSet<String> = vec![String::from("b.json")].into_iter().collect();
+        assert_eq!(expected, changed_files);
+    }
 }
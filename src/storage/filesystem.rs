@@ -0,0 +1,398 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use once_cell::sync::OnceCell;
+use tokio::fs;
+use tokio::stream::StreamExt;
+
+use super::{
+    write_atomically, write_images_to, Area, EditedEntries, Storage, SyncState, BACKUP_AREAS,
+    BACKUP_DIR_PREFIX, EDITED_ENTRIES_FILE, IMAGE_DIR, PAGE_DIR, SYNC_BASE_DIR, SYNC_STATE_FILE,
+    TRASH_DIR,
+};
+use crate::crypto;
+
+// ディレクトリにページや画像を保存する
+pub struct FsStorage {
+    directory: PathBuf,
+    // 暗号化の鍵。必要になったときにパスフレーズを入力させる
+    key: OnceCell<crypto::Key>,
+}
+
+impl FsStorage {
+    pub fn new(directory: &Path) -> Self {
+        Self {
+            directory: directory.to_path_buf(),
+            key: OnceCell::new(),
+        }
+    }
+
+    fn area_dir(&self, area: Area) -> PathBuf {
+        match area {
+            Area::Pages => self.directory.join(PAGE_DIR),
+            Area::Images => self.directory.join(IMAGE_DIR),
+            Area::TrashPages => self.directory.join(TRASH_DIR).join(PAGE_DIR),
+            Area::TrashImages => self.directory.join(TRASH_DIR).join(IMAGE_DIR),
+            Area::SyncBase => self.directory.join(SYNC_BASE_DIR),
+        }
+    }
+
+    pub fn path(&self, area: Area, file_name: &str) -> PathBuf {
+        self.area_dir(area).join(file_name)
+    }
+
+    fn key(&self) -> Result<&crypto::Key> {
+        self.key.get_or_try_init(|| crypto::unlock(&self.directory))
+    }
+
+    // ==============================
+    // 暗号化
+    // ==============================
+
+    // 暗号化の対象になるファイルのパスを取得する
+    async fn data_file_paths(&self) -> Result<Vec<PathBuf>> {
+        let mut paths = Vec::new();
+
+        for &area in &[
+            Area::Pages,
+            Area::TrashPages,
+            Area::Images,
+            Area::TrashImages,
+            Area::SyncBase,
+        ] {
+            for file_name in self.list(area).await? {
+                paths.push(self.path(area, &file_name));
+            }
+        }
+
+        Ok(paths)
+    }
+
+    // ==============================
+    // バックアップ
+    // ==============================
+
+    fn generate_backup_dir_path(&self, id: u32) -> PathBuf {
+        self.directory
+            .join(&format!("{}_{}", BACKUP_DIR_PREFIX, id))
+    }
+
+    fn generate_backup_dir_path_not_exists(&self) -> (PathBuf, u32) {
+        let mut id = 1;
+        loop {
+            let path = self.generate_backup_dir_path(id);
+            if !path.exists() {
+                return (path, id);
+            }
+
+            id += 1;
+        }
+    }
+
+    // バックアップ内の種類ごとのディレクトリ。ページはバックアップの直下に置く
+    fn backup_area_dir(backup_dir: &Path, area: Area) -> PathBuf {
+        match area {
+            Area::Pages => backup_dir.to_path_buf(),
+            Area::TrashPages => backup_dir.join(TRASH_DIR),
+            _ => unreachable!("{:?}", area),
+        }
+    }
+}
+
+// ディレクトリ内のすべてのファイルをコピーする。コピー元がなければ空のディレクトリを作成する
+async fn copy_files(from: &Path, to: &Path) -> Result<()> {
+    fs::create_dir_all(to).await?;
+    if !from.exists() {
+        return Ok(());
+    }
+
+    let mut entries = fs::read_dir(from).await?;
+    while let Some(entry) = entries.next().await {
+        let entry = entry?;
+        if let Ok(ft) = entry.file_type().await {
+            if ft.is_file() {
+                fs::copy(entry.path(), to.join(entry.file_name())).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[async_trait]
+impl Storage for FsStorage {
+    async fn list(&self, area: Area) -> Result<Vec<String>> {
+        let dir = self.area_dir(area);
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut file_names = Vec::new();
+
+        let mut entries = fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next().await {
+            let entry = entry?;
+            if entry.file_type().await?.is_file() {
+                file_names.push(entry.file_name().to_string_lossy().to_string());
+            }
+        }
+
+        Ok(file_names)
+    }
+
+    async fn exists(&self, area: Area, file_name: &str) -> Result<bool> {
+        Ok(self.path(area, file_name).exists())
+    }
+
+    async fn read(&self, area: Area, file_name: &str) -> Result<Vec<u8>> {
+        let data = fs::read(self.path(area, file_name)).await?;
+        self.decrypt_if_needed(data)
+    }
+
+    async fn write(&self, area: Area, file_name: &str, contents: &[u8]) -> Result<()> {
+        fs::create_dir_all(self.area_dir(area)).await?;
+
+        let path = self.path(area, file_name);
+        write_atomically(&path, &self.encrypt_if_enabled(contents)?).await
+    }
+
+    async fn write_from_file(&self, area: Area, file_name: &str, source: &Path) -> Result<()> {
+        // 暗号化する場合は読み込むしかない
+        if self.is_encrypted() {
+            let contents = fs::read(source).await?;
+            self.write(area, file_name, &contents).await?;
+            fs::remove_file(source).await?;
+            return Ok(());
+        }
+
+        fs::create_dir_all(self.area_dir(area)).await?;
+
+        // 別のファイルシステムにある場合は移動できないので、コピーしてから置き換える
+        let path = self.path(area, file_name);
+        if fs::rename(source, &path).await.is_err() {
+            let mut temp_file_name = path.file_name().unwrap().to_os_string();
+            temp_file_name.push(".tmp");
+            let temp_file_path = path.with_file_name(temp_file_name);
+
+            fs::copy(source, &temp_file_path).await?;
+            fs::rename(&temp_file_path, &path).await?;
+            fs::remove_file(source).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn remove(&self, area: Area, file_name: &str) -> Result<()> {
+        fs::remove_file(self.path(area, file_name)).await?;
+        Ok(())
+    }
+
+    async fn rename(&self, from: Area, from_name: &str, to: Area, to_name: &str) -> Result<()> {
+        fs::create_dir_all(self.area_dir(to)).await?;
+        fs::rename(self.path(from, from_name), self.path(to, to_name)).await?;
+        Ok(())
+    }
+
+    async fn edited_entries(&self) -> Result<EditedEntries> {
+        let file_path = self.directory.join(EDITED_ENTRIES_FILE);
+
+        if file_path.exists() {
+            let json = fs::read_to_string(&file_path).await?;
+            let entries: EditedEntries = serde_json::from_str(&json)?;
+            Ok(entries)
+        } else {
+            Ok(EditedEntries::new())
+        }
+    }
+
+    async fn write_edited_entries(&self, entries: &EditedEntries) -> Result<()> {
+        let file_path = self.directory.join(EDITED_ENTRIES_FILE);
+        let json = serde_json::to_string(entries)?;
+        fs::write(&file_path, json).await?;
+
+        Ok(())
+    }
+
+    async fn sync_state(&self) -> Result<SyncState> {
+        let file_path = self.directory.join(SYNC_STATE_FILE);
+
+        if file_path.exists() {
+            let json = fs::read_to_string(&file_path).await?;
+            let state: SyncState = serde_json::from_str(&json)?;
+            Ok(state)
+        } else {
+            Ok(SyncState::default())
+        }
+    }
+
+    async fn write_sync_state(&self, state: &SyncState) -> Result<()> {
+        let file_path = self.directory.join(SYNC_STATE_FILE);
+        let json = serde_json::to_string(state)?;
+        write_atomically(&file_path, json.as_bytes()).await
+    }
+
+    // ページとゴミ箱のページをバックアップする。どちらも変換で書き換えられる
+    async fn create_pages_backup(&self) -> Result<u32> {
+        let (backup_dir, id) = self.generate_backup_dir_path_not_exists();
+        fs::create_dir(&backup_dir).await?;
+
+        for &area in BACKUP_AREAS {
+            copy_files(
+                &self.area_dir(area),
+                &Self::backup_area_dir(&backup_dir, area),
+            )
+            .await?;
+        }
+
+        Ok(id)
+    }
+
+    async fn rollback(&self, id: u32) -> Result<()> {
+        let backup_dir = self.generate_backup_dir_path(id);
+
+        for &area in BACKUP_AREAS {
+            let area_dir = self.area_dir(area);
+            if area_dir.exists() {
+                fs::remove_dir_all(&area_dir).await?;
+            }
+
+            copy_files(&Self::backup_area_dir(&backup_dir, area), &area_dir).await?;
+        }
+
+        self.remove_pages_backup(id).await?;
+
+        Ok(())
+    }
+
+    async fn remove_pages_backup(&self, id: u32) -> Result<()> {
+        let backup_dir = self.generate_backup_dir_path(id);
+        fs::remove_dir_all(backup_dir).await?;
+
+        Ok(())
+    }
+
+    fn pages_backup_location(&self, id: u32) -> String {
+        self.generate_backup_dir_path(id).display().to_string()
+    }
+
+    fn is_encrypted(&self) -> bool {
+        crypto::is_enabled(&self.directory)
+    }
+
+    fn enable_encryption(&self, passphrase: &str) -> Result<()> {
+        let key = crypto::enable(&self.directory, passphrase)?;
+        let _ = self.key.set(key);
+
+        Ok(())
+    }
+
+    fn disable_encryption(&self) -> Result<()> {
+        crypto::disable(&self.directory)
+    }
+
+    // 暗号化されていないファイルをすべて暗号化して、暗号化した数を返す
+    async fn encrypt_all(&self) -> Result<usize> {
+        let key = self.key()?;
+        let mut count = 0;
+
+        for path in self.data_file_paths().await? {
+            let data = fs::read(&path).await?;
+            if crypto::is_encrypted(&data) {
+                continue;
+            }
+
+            write_atomically(&path, &crypto::encrypt(key, &data)?).await?;
+            count += 1;
+        }
+
+        Ok(count)
+    }
+
+    // 暗号化されたファイルをすべて復号して、復号した数を返す
+    async fn decrypt_all(&self) -> Result<usize> {
+        let key = self.key()?;
+        let mut count = 0;
+
+        for path in self.data_file_paths().await? {
+            let data = fs::read(&path).await?;
+            if !crypto::is_encrypted(&data) {
+                continue;
+            }
+
+            write_atomically(&path, &crypto::decrypt(key, &data)?).await?;
+            count += 1;
+        }
+
+        Ok(count)
+    }
+
+    // 暗号化されていれば復号する
+    fn decrypt_if_needed(&self, data: Vec<u8>) -> Result<Vec<u8>> {
+        if crypto::is_encrypted(&data) {
+            crypto::decrypt(self.key()?, &data)
+        } else {
+            Ok(data)
+        }
+    }
+
+    // 暗号化が有効なら暗号化する
+    fn encrypt_if_enabled(&self, contents: &[u8]) -> Result<Vec<u8>> {
+        if self.is_encrypted() {
+            crypto::encrypt(self.key()?, contents)
+        } else {
+            Ok(contents.to_vec())
+        }
+    }
+
+    // 暗号化されていなければ画像のディレクトリをそのまま使う
+    async fn viewable_image_dir(&self, file_names: &[String], temp_dir: &Path) -> Result<PathBuf> {
+        if !self.is_encrypted() {
+            return Ok(self.area_dir(Area::Images));
+        }
+
+        write_images_to(self, file_names, temp_dir).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[tokio::test]
+    async fn test_rollback_restores_trash() {
+        let dir = TempDir::new();
+        let storage = FsStorage::new(dir.path());
+        storage.write(Area::Pages, "week.json", b"1").await.unwrap();
+        storage
+            .write(Area::TrashPages, "trashed.json", b"1")
+            .await
+            .unwrap();
+
+        let id = storage.create_pages_backup().await.unwrap();
+
+        // 途中まで書き換えてから元に戻す
+        storage.write(Area::Pages, "week.json", b"2").await.unwrap();
+        storage
+            .write(Area::TrashPages, "trashed.json", b"2")
+            .await
+            .unwrap();
+        storage.write(Area::Pages, "new.json", b"2").await.unwrap();
+        storage.rollback(id).await.unwrap();
+
+        assert_eq!(
+            b"1".to_vec(),
+            storage.read(Area::Pages, "week.json").await.unwrap()
+        );
+        assert_eq!(
+            b"1".to_vec(),
+            storage
+                .read(Area::TrashPages, "trashed.json")
+                .await
+                .unwrap()
+        );
+        assert!(!storage.exists(Area::Pages, "new.json").await.unwrap());
+        assert!(!storage.generate_backup_dir_path(id).exists());
+    }
+}
@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+
+use super::{Area, EditedEntries, Storage, SyncState, BACKUP_AREAS};
+
+type FileKey = (Area, String);
+
+// メモリ上にページや画像を保存する。テストで使う
+#[derive(Default)]
+pub struct MemoryStorage {
+    files: Mutex<HashMap<FileKey, Vec<u8>>>,
+    edited_entries: Mutex<EditedEntries>,
+    sync_state: Mutex<SyncState>,
+    backups: Mutex<HashMap<u32, HashMap<FileKey, Vec<u8>>>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn list(&self, area: Area) -> Result<Vec<String>> {
+        let files = self.files.lock().unwrap();
+        Ok(files
+            .keys()
+            .filter(|(a, _)| *a == area)
+            .map(|(_, file_name)| file_name.clone())
+            .collect())
+    }
+
+    async fn exists(&self, area: Area, file_name: &str) -> Result<bool> {
+        let files = self.files.lock().unwrap();
+        Ok(files.contains_key(&(area, file_name.to_string())))
+    }
+
+    async fn read(&self, area: Area, file_name: &str) -> Result<Vec<u8>> {
+        let files = self.files.lock().unwrap();
+        files
+            .get(&(area, file_name.to_string()))
+            .cloned()
+            .ok_or_else(|| anyhow!("{}が存在しません", file_name))
+    }
+
+    async fn write(&self, area: Area, file_name: &str, contents: &[u8]) -> Result<()> {
+        let mut files = self.files.lock().unwrap();
+        files.insert((area, file_name.to_string()), contents.to_vec());
+        Ok(())
+    }
+
+    async fn write_from_file(&self, area: Area, file_name: &str, source: &Path) -> Result<()> {
+        let contents = fs::read(source)?;
+        fs::remove_file(source)?;
+        self.write(area, file_name, &contents).await
+    }
+
+    async fn remove(&self, area: Area, file_name: &str) -> Result<()> {
+        let mut files = self.files.lock().unwrap();
+        files
+            .remove(&(area, file_name.to_string()))
+            .ok_or_else(|| anyhow!("{}が存在しません", file_name))?;
+        Ok(())
+    }
+
+    async fn rename(&self, from: Area, from_name: &str, to: Area, to_name: &str) -> Result<()> {
+        let mut files = self.files.lock().unwrap();
+        let contents = files
+            .remove(&(from, from_name.to_string()))
+            .ok_or_else(|| anyhow!("{}が存在しません", from_name))?;
+        files.insert((to, to_name.to_string()), contents);
+        Ok(())
+    }
+
+    async fn edited_entries(&self) -> Result<EditedEntries> {
+        Ok(self.edited_entries.lock().unwrap().clone())
+    }
+
+    async fn write_edited_entries(&self, entries: &EditedEntries) -> Result<()> {
+        *self.edited_entries.lock().unwrap() = entries.clone();
+        Ok(())
+    }
+
+    async fn sync_state(&self) -> Result<SyncState> {
+        Ok(self.sync_state.lock().unwrap().clone())
+    }
+
+    async fn write_sync_state(&self, state: &SyncState) -> Result<()> {
+        *self.sync_state.lock().unwrap() = state.clone();
+        Ok(())
+    }
+
+    async fn create_pages_backup(&self) -> Result<u32> {
+        let files = self.files.lock().unwrap();
+        let backup = files
+            .iter()
+            .filter(|((area, _), _)| BACKUP_AREAS.contains(area))
+            .map(|(key, contents)| (key.clone(), contents.clone()))
+            .collect();
+
+        let mut backups = self.backups.lock().unwrap();
+        let id = (1..).find(|id| !backups.contains_key(id)).unwrap();
+        backups.insert(id, backup);
+
+        Ok(id)
+    }
+
+    async fn rollback(&self, id: u32) -> Result<()> {
+        let backup = self
+            .backups
+            .lock()
+            .unwrap()
+            .remove(&id)
+            .ok_or_else(|| anyhow!("バックアップ{}が存在しません", id))?;
+
+        let mut files = self.files.lock().unwrap();
+        files.retain(|(area, _), _| !BACKUP_AREAS.contains(area));
+        files.extend(backup);
+
+        Ok(())
+    }
+
+    async fn remove_pages_backup(&self, id: u32) -> Result<()> {
+        self.backups
+            .lock()
+            .unwrap()
+            .remove(&id)
+            .ok_or_else(|| anyhow!("バックアップ{}が存在しません", id))?;
+        Ok(())
+    }
+
+    fn pages_backup_location(&self, id: u32) -> String {
+        format!("バックアップ{}", id)
+    }
+}
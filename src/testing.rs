@@ -0,0 +1,47 @@
+// テストで共通して使うもの
+
+use std::env;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+
+use crate::page::Page;
+
+// タイトルがIDと同じで本文が空のページ
+pub(crate) fn page(id: &str, created_at: DateTime<Utc>) -> Page {
+    Page {
+        id: id.to_string(),
+        title: id.to_string(),
+        text: String::new(),
+        tags: Vec::new(),
+        hidden: false,
+        created_at,
+        updated_at: vec![created_at],
+        revisions: Vec::new(),
+        sealed: None,
+    }
+}
+
+// 一時ディレクトリのパス。作成は使う側に任せ、ドロップしたときに中身ごと削除する
+pub(crate) struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub(crate) fn new() -> Self {
+        TempDir {
+            path: env::temp_dir().join(uuid::Uuid::new_v4().to_string()),
+        }
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        // 作成されなかった場合もある
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}